@@ -0,0 +1,115 @@
+use anyhow::{bail, Context, Result};
+
+use crate::{command::CommandRunner, config::SystemConfig};
+
+/// The account user-level changes are made for when running as root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub user: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Checks that the invoking user matches `primary_user`.
+///
+/// Returns the account to act as when invoked as root on behalf of another
+/// user, and `None` when commands can run as the invoking user directly.
+pub fn resolve(system: &SystemConfig, cmd: &dyn CommandRunner) -> Result<Option<Account>> {
+    let primary = system.primary_user.as_str();
+    if is_root(cmd)? {
+        if primary == "root" {
+            return Ok(None);
+        }
+        let account = lookup(cmd, primary)
+            .with_context(|| format!("primary_user `{primary}` does not exist on this machine"))?;
+        return Ok(Some(account));
+    }
+
+    let current = id(cmd, &["-un"])?;
+    if current != primary {
+        bail!(
+            "config is for primary_user `{primary}` but setup-my-mac is running as `{current}`; \
+             run it as {primary}, or as root to act on their behalf"
+        );
+    }
+    Ok(None)
+}
+
+/// Whether the tool runs as root.
+pub fn is_root(cmd: &dyn CommandRunner) -> Result<bool> {
+    Ok(id(cmd, &["-u"])? == "0")
+}
+
+/// The uid and gid of `user`.
+pub fn lookup(cmd: &dyn CommandRunner, user: &str) -> Result<Account> {
+    let uid = id(cmd, &["-u", user])?;
+    let gid = id(cmd, &["-g", user])?;
+    Ok(Account {
+        user: user.to_owned(),
+        uid: parse_id(&uid)?,
+        gid: parse_id(&gid)?,
+    })
+}
+
+fn id(cmd: &dyn CommandRunner, args: &[&str]) -> Result<String> {
+    let output = cmd.output("id", args)?;
+    if !output.success() {
+        bail!("`id {}` failed: {}", args.join(" "), output.stderr.trim());
+    }
+    Ok(output.stdout.trim().to_owned())
+}
+
+fn parse_id(value: &str) -> Result<u32> {
+    value
+        .parse()
+        .with_context(|| format!("unexpected id `{value}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::ScriptedRunner, steps::testing};
+
+    #[test]
+    fn matching_user_runs_directly() {
+        let runner = ScriptedRunner::new();
+        runner.on("id -u", 0, "501\n").on("id -un", 0, "test\n");
+
+        let cfg = testing::config("");
+        assert_eq!(resolve(&cfg.system, &runner).unwrap(), None);
+    }
+
+    #[test]
+    fn other_users_are_refused() {
+        let runner = ScriptedRunner::new();
+        runner.on("id -u", 0, "502\n").on("id -un", 0, "guest\n");
+
+        let cfg = testing::config("");
+        let err = resolve(&cfg.system, &runner).unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "config is for primary_user `test` but setup-my-mac is running as `guest`"
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn root_acts_as_primary_user() {
+        let runner = ScriptedRunner::new();
+        runner
+            .on("id -u", 0, "0\n")
+            .on("id -u test", 0, "501\n")
+            .on("id -g test", 0, "20\n");
+
+        let cfg = testing::config("");
+        assert_eq!(
+            resolve(&cfg.system, &runner).unwrap(),
+            Some(Account {
+                user: "test".to_owned(),
+                uid: 501,
+                gid: 20,
+            })
+        );
+    }
+}
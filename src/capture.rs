@@ -0,0 +1,408 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use toml::{Table, Value};
+use tracing::warn;
+
+use crate::{
+    command::CommandRunner,
+    defaults::{self, DefaultsValue, Key},
+    steps::{git, system},
+    template,
+    util::{self, reroot, resolve_path_in_home},
+};
+
+/// Git keys the git step manages, with where they live in `[user.git]`.
+const GIT_STRING_KEYS: [(&str, &[&str]); 5] = [
+    ("user.name", &["user_name"]),
+    ("user.email", &["user_email"]),
+    ("credential.helper", &["credential_helper"]),
+    ("init.defaultBranch", &["init", "default_branch"]),
+    ("merge.conflictStyle", &["merge", "conflictstyle"]),
+];
+const GIT_BOOL_KEYS: [(&str, &[&str]); 2] = [
+    ("pull.rebase", &["pull", "rebase"]),
+    ("push.autoSetupRemote", &["push", "auto_setup_remote"]),
+];
+
+/// The machine `capture` reads from.
+pub struct Machine<'a> {
+    pub cmd: &'a dyn CommandRunner,
+    pub user: String,
+    pub home: PathBuf,
+    /// `--root-dir`: read files from a staged tree instead, skipping
+    /// anything that cannot be staged.
+    pub stage: Option<&'a Path>,
+}
+
+impl<'a> Machine<'a> {
+    /// The machine of the user running the tool, or of the user who ran
+    /// `sudo` when running under it. `cmd` should run commands as them.
+    pub fn current(cmd: &'a dyn CommandRunner, stage: Option<&'a Path>) -> Self {
+        let user = util::invoking_user().unwrap_or_default();
+        let home = util::home_of(&user)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(shellexpand::tilde("~").as_ref()));
+        Machine {
+            cmd,
+            user,
+            home,
+            stage,
+        }
+    }
+
+    fn path(&self, path: &Path) -> PathBuf {
+        match self.stage {
+            Some(stage) => reroot(path, stage),
+            None => path.to_path_buf(),
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<Option<String>> {
+        let path = self.path(path);
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+}
+
+/// Reads the machine's current settings into a config file that
+/// `load_config` accepts.
+pub fn capture(machine: &Machine<'_>) -> Result<String> {
+    let mut config = Table::new();
+    config.insert("system".into(), Value::Table(capture_system(machine)?));
+    if machine.stage.is_none() {
+        if let Some(homebrew) = capture_homebrew(machine.cmd) {
+            config.insert("homebrew".into(), Value::Table(homebrew));
+        }
+    }
+
+    let mut user = Table::new();
+    if let Some(ssh) = machine.read(&machine.home.join(".ssh/config"))? {
+        let ssh = table_of([("config", Value::String(ssh))]);
+        user.insert("ssh".into(), Value::Table(ssh));
+    }
+    if let Some(git) = capture_git(machine)? {
+        user.insert("git".into(), Value::Table(git));
+    }
+    config.insert("user".into(), Value::Table(user));
+    // Loading expands `{{ }}`, so captured text must not be read as a template.
+    template::escape_table(&mut config);
+
+    let body = toml::to_string_pretty(&config).context("serializing captured config")?;
+    Ok(format!(
+        "# Captured by `setup-my-mac capture`; review before applying it elsewhere.\n\n{body}"
+    ))
+}
+
+fn capture_system(machine: &Machine<'_>) -> Result<Table> {
+    let home = machine
+        .home
+        .to_str()
+        .context("home directory contains invalid UTF-8")?;
+    let mut system = table_of([
+        ("home_directory", Value::String(home.to_owned())),
+        ("primary_user", Value::String(machine.user.clone())),
+    ]);
+
+    if let Some(tz) = timezone(&machine.path(Path::new("/etc/localtime"))) {
+        system.insert("timezone".into(), Value::String(tz));
+    }
+
+    let touch_id = machine
+        .read(Path::new("/etc/pam.d/sudo_local"))?
+        .is_some_and(|pam| {
+            pam.lines()
+                .any(|line| !line.trim_start().starts_with('#') && line.contains("pam_tid.so"))
+        });
+    if touch_id {
+        system.insert("touch_id_sudo".into(), Value::Boolean(true));
+    }
+
+    // Trackpad prefs live in the defaults database, which cannot be staged.
+    if machine.stage.is_none() {
+        let mut reader = defaults::Reader::new(machine.cmd);
+        let mut trackpad = Table::new();
+        for (key, field) in [
+            ("Clicking", "clicking"),
+            ("TrackpadThreeFingerDrag", "three_finger_drag"),
+        ] {
+            let key = Key {
+                domain: system::TRACKPAD_DOMAINS[0],
+                key,
+                current_host: false,
+            };
+            match reader.read(key) {
+                Ok(Some(DefaultsValue::Bool(value))) => {
+                    trackpad.insert(field.into(), Value::Boolean(value));
+                }
+                Ok(_) => {}
+                Err(err) => warn!("unable to read trackpad settings: {err:#}"),
+            }
+        }
+        if !trackpad.is_empty() {
+            system.insert("trackpad".into(), Value::Table(trackpad));
+        }
+    }
+
+    Ok(system)
+}
+
+/// The IANA name `/etc/localtime` links to, e.g.
+/// `/var/db/timezone/zoneinfo/Europe/Paris` -> `Europe/Paris`.
+pub fn timezone(localtime: &Path) -> Option<String> {
+    let link = fs::read_link(localtime).ok()?;
+    let link = link.to_str()?;
+    let (_, name) = link.split_once("zoneinfo/")?;
+    Some(name.to_owned())
+}
+
+fn capture_homebrew(cmd: &dyn CommandRunner) -> Option<Table> {
+    let output = match cmd.output("brew", &["bundle", "dump", "--file=-"]) {
+        Ok(output) if output.success() => output,
+        Ok(output) => {
+            warn!(
+                "brew bundle dump failed, skipping Homebrew: {}",
+                output.stderr.trim()
+            );
+            return None;
+        }
+        Err(err) => {
+            warn!("Homebrew not found, skipping it: {err:#}");
+            return None;
+        }
+    };
+
+    let (brews, casks) = parse_brewfile(&output.stdout);
+    Some(table_of([
+        ("enable", Value::Boolean(true)),
+        ("brews", strings(brews)),
+        ("casks", strings(casks)),
+    ]))
+}
+
+/// Formula and cask names from a Brewfile; taps, `mas` and other entries
+/// have no config equivalent and are dropped.
+fn parse_brewfile(brewfile: &str) -> (Vec<String>, Vec<String>) {
+    let mut brews = Vec::new();
+    let mut casks = Vec::new();
+    for line in brewfile.lines() {
+        let Some((kind, rest)) = line.trim().split_once(' ') else {
+            continue;
+        };
+        let list = match kind {
+            "brew" => &mut brews,
+            "cask" => &mut casks,
+            _ => continue,
+        };
+        if let Some(name) = rest
+            .trim()
+            .strip_prefix('"')
+            .and_then(|rest| rest.split('"').next())
+        {
+            list.push(name.to_owned());
+        }
+    }
+    (brews, casks)
+}
+
+fn capture_git(machine: &Machine<'_>) -> Result<Option<Table>> {
+    // On a fresh Mac, `git` is a stub that exits non-zero until the command
+    // line tools are installed.
+    match machine.cmd.output("git", &["--version"]) {
+        Ok(output) if output.success() => {}
+        Ok(output) => {
+            warn!(
+                "git --version failed, skipping git: {}",
+                output.stderr.trim()
+            );
+            return Ok(None);
+        }
+        Err(err) => {
+            warn!("git not found, skipping it: {err:#}");
+            return Ok(None);
+        }
+    }
+
+    // A staged tree keeps git keys in its own `.gitconfig`.
+    let file = machine
+        .stage
+        .map(|stage| reroot(&machine.home.join(".gitconfig"), stage));
+    let file = file.as_deref();
+
+    let mut git = Table::new();
+    for (key, field) in GIT_STRING_KEYS {
+        if let Some(value) = git::git_get(machine.cmd, file, key)? {
+            insert_at(&mut git, field, Value::String(value));
+        }
+    }
+    for (key, field) in GIT_BOOL_KEYS {
+        // Values like `pull.rebase = merges` have no config equivalent.
+        let value = match git::git_get(machine.cmd, file, key)?.as_deref() {
+            Some("true") => true,
+            Some("false") => false,
+            _ => continue,
+        };
+        insert_at(&mut git, field, Value::Boolean(value));
+    }
+
+    if let Some(excludes) = git::git_get(machine.cmd, file, "core.excludesFile")? {
+        let path = resolve_path_in_home(&excludes, &machine.home, &machine.home)?;
+        if let Some(contents) = machine.read(&path)? {
+            let ignores: Vec<String> = contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_owned)
+                .collect();
+            if !ignores.is_empty() {
+                git.insert("ignores".into(), strings(ignores));
+            }
+        }
+    }
+
+    if git.is_empty() {
+        return Ok(None);
+    }
+    git.insert("enable".into(), Value::Boolean(true));
+    Ok(Some(git))
+}
+
+fn insert_at(table: &mut Table, path: &[&str], value: Value) {
+    match path {
+        [key] => {
+            table.insert((*key).to_owned(), value);
+        }
+        [key, rest @ ..] => {
+            let child = table
+                .entry((*key).to_owned())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if let Value::Table(child) = child {
+                insert_at(child, rest, value);
+            }
+        }
+        [] => {}
+    }
+}
+
+fn table_of<const N: usize>(entries: [(&str, Value); N]) -> Table {
+    entries
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value))
+        .collect()
+}
+
+fn strings(values: Vec<String>) -> Value {
+    Value::Array(values.into_iter().map(Value::String).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::ScriptedRunner, config};
+
+    #[test]
+    fn brewfile_entries_become_brews_and_casks() {
+        let brewfile = r#"tap "homebrew/bundle"
+brew "git"
+brew "ripgrep", link: false
+cask "iterm2"
+mas "Xcode", id: 497799835
+"#;
+        assert_eq!(
+            parse_brewfile(brewfile),
+            (
+                vec!["git".to_owned(), "ripgrep".to_owned()],
+                vec!["iterm2".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn captured_config_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = Path::new("/Users/jane");
+        let staged_home = dir.path().join("Users/jane");
+        fs::create_dir_all(staged_home.join(".ssh")).unwrap();
+        fs::create_dir_all(staged_home.join(".config/git")).unwrap();
+        fs::create_dir_all(dir.path().join("etc/pam.d")).unwrap();
+        fs::write(
+            staged_home.join(".ssh/config"),
+            "Host *\n  AddKeysToAgent yes\n  SetEnv GREETING={{hi}}\n",
+        )
+        .unwrap();
+        fs::write(
+            staged_home.join(".config/git/ignore"),
+            "# mine\n.DS_Store\ntarget/\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("etc/pam.d/sudo_local"),
+            "auth       sufficient     pam_tid.so\n",
+        )
+        .unwrap();
+
+        let gitconfig = staged_home.join(".gitconfig");
+        let get = |key: &str| format!("git config --file {} --get {key}", gitconfig.display());
+        let runner = ScriptedRunner::new();
+        runner.on("git --version", 0, "git version 2.39.3\n");
+        for (key, _) in GIT_STRING_KEYS.iter().chain(&GIT_BOOL_KEYS) {
+            runner.on(&get(key), 1, "");
+        }
+        runner
+            .on(&get("user.name"), 0, "Jane\n")
+            .on(&get("pull.rebase"), 0, "true\n")
+            .on(&get("core.excludesFile"), 0, "~/.config/git/ignore\n");
+
+        let machine = Machine {
+            cmd: &runner,
+            user: "jane".to_owned(),
+            home: home.to_owned(),
+            stage: Some(dir.path()),
+        };
+        let captured = capture(&machine).unwrap();
+
+        let path = dir.path().join("config.toml");
+        fs::write(&path, &captured).unwrap();
+        let cfg = config::load_config(&path, None).unwrap().config;
+        assert_eq!(cfg.system.home_directory, home);
+        assert_eq!(cfg.system.primary_user, "jane");
+        assert!(cfg.system.touch_id_sudo);
+        assert_eq!(
+            cfg.user.ssh.unwrap().config,
+            "Host *\n  AddKeysToAgent yes\n  SetEnv GREETING={{hi}}\n"
+        );
+        let git = cfg.user.git.unwrap();
+        assert!(git.enable);
+        assert_eq!(git.user_name.as_deref(), Some("Jane"));
+        assert_eq!(git.pull.unwrap().rebase, Some(true));
+        assert_eq!(git.ignores, [".DS_Store", "target/"]);
+    }
+
+    #[test]
+    fn missing_git_skips_the_git_section() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = ScriptedRunner::new();
+        let machine = Machine {
+            cmd: &runner,
+            user: "jane".to_owned(),
+            home: PathBuf::from("/Users/jane"),
+            stage: Some(dir.path()),
+        };
+
+        let captured = capture(&machine).unwrap();
+        assert!(!captured.contains("[user.git]"), "{captured}");
+        assert_eq!(runner.calls(), ["git --version"]);
+
+        runner.on("git --version", 1, "");
+        let captured = capture(&machine).unwrap();
+        assert!(!captured.contains("[user.git]"), "{captured}");
+        assert_eq!(runner.calls(), ["git --version", "git --version"]);
+    }
+}
@@ -0,0 +1,366 @@
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// Result of running an external command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Exit code, or `None` when the process was killed by a signal.
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+impl From<Output> for CommandOutput {
+    fn from(output: Output) -> Self {
+        CommandOutput {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Executes external programs on behalf of steps.
+///
+/// Steps never spawn processes directly so they can run against
+/// [`ScriptedRunner`] in tests on any platform.
+pub trait CommandRunner {
+    /// Runs to completion, capturing stdout and stderr.
+    fn output(&self, program: &str, args: &[&str]) -> Result<CommandOutput>;
+
+    /// Runs attached to the terminal so the command can prompt the user.
+    /// Output is not captured.
+    fn interactive(&self, program: &str, args: &[&str]) -> Result<CommandOutput>;
+
+    /// Runs with stdin inherited, handing each output line to `on_line` as it
+    /// arrives.
+    fn stream(
+        &self,
+        program: &str,
+        args: &[&str],
+        on_line: &(dyn Fn(Stream, &str) + Sync),
+    ) -> Result<CommandOutput>;
+}
+
+/// Spawns real processes.
+#[derive(Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .with_context(|| format!("failed to spawn {program}"))?;
+        Ok(output.into())
+    }
+
+    fn interactive(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let status = Command::new(program)
+            .args(args)
+            .status()
+            .with_context(|| format!("failed to spawn {program}"))?;
+        Ok(CommandOutput {
+            code: status.code(),
+            ..CommandOutput::default()
+        })
+    }
+
+    fn stream(
+        &self,
+        program: &str,
+        args: &[&str],
+        on_line: &(dyn Fn(Stream, &str) + Sync),
+    ) -> Result<CommandOutput> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to spawn {program}"))?;
+
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let (stdout, stderr) = thread::scope(|scope| {
+            let out = scope.spawn(|| stdout.map(|r| forward_lines(r, Stream::Stdout, on_line)));
+            let err = scope.spawn(|| stderr.map(|r| forward_lines(r, Stream::Stderr, on_line)));
+            (
+                out.join().unwrap_or_default().unwrap_or_default(),
+                err.join().unwrap_or_default().unwrap_or_default(),
+            )
+        });
+
+        let status = child
+            .wait()
+            .with_context(|| format!("failed to wait for {program}"))?;
+
+        Ok(CommandOutput {
+            code: status.code(),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+fn forward_lines(
+    reader: impl Read,
+    stream: Stream,
+    on_line: &(dyn Fn(Stream, &str) + Sync),
+) -> String {
+    let mut collected = String::new();
+    for line in BufReader::new(reader).lines() {
+        match line {
+            Ok(line) => {
+                on_line(stream, &line);
+                collected.push_str(&line);
+                collected.push('\n');
+            }
+            Err(err) => {
+                on_line(Stream::Stderr, &format!("stream read error: {err}"));
+                break;
+            }
+        }
+    }
+    collected
+}
+
+/// A command that was executed, as it appears in run reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommandRecord {
+    pub program: String,
+    pub args: Vec<String>,
+    pub exit_code: Option<i32>,
+}
+
+/// Wraps another runner and remembers every command it executes.
+pub struct RecordingRunner {
+    inner: Box<dyn CommandRunner>,
+    log: RefCell<Vec<CommandRecord>>,
+}
+
+impl RecordingRunner {
+    pub fn new(inner: impl CommandRunner + 'static) -> Self {
+        RecordingRunner {
+            inner: Box::new(inner),
+            log: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Returns the commands executed since the last call.
+    pub fn take(&self) -> Vec<CommandRecord> {
+        std::mem::take(&mut self.log.borrow_mut())
+    }
+
+    fn record(&self, program: &str, args: &[&str], result: &Result<CommandOutput>) {
+        self.log.borrow_mut().push(CommandRecord {
+            program: program.to_owned(),
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+            exit_code: result.as_ref().ok().and_then(|output| output.code),
+        });
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn output(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let result = self.inner.output(program, args);
+        self.record(program, args, &result);
+        result
+    }
+
+    fn interactive(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let result = self.inner.interactive(program, args);
+        self.record(program, args, &result);
+        result
+    }
+
+    fn stream(
+        &self,
+        program: &str,
+        args: &[&str],
+        on_line: &(dyn Fn(Stream, &str) + Sync),
+    ) -> Result<CommandOutput> {
+        let result = self.inner.stream(program, args, on_line);
+        self.record(program, args, &result);
+        result
+    }
+}
+
+/// Runs commands as another user through `sudo -u`, so a run started as
+/// root can act on the primary user's behalf. Passes commands through
+/// unchanged when no user is set.
+pub struct AsUser<'a> {
+    inner: &'a dyn CommandRunner,
+    user: Option<&'a str>,
+}
+
+impl<'a> AsUser<'a> {
+    pub fn new(inner: &'a dyn CommandRunner, user: Option<&'a str>) -> Self {
+        AsUser { inner, user }
+    }
+
+    fn wrap<'b>(&'b self, program: &'b str, args: &[&'b str]) -> (&'b str, Vec<&'b str>) {
+        match self.user {
+            Some(user) => {
+                let mut wrapped = vec!["-u", user, "-H", "--", program];
+                wrapped.extend_from_slice(args);
+                ("sudo", wrapped)
+            }
+            None => (program, args.to_vec()),
+        }
+    }
+}
+
+impl CommandRunner for AsUser<'_> {
+    fn output(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let (program, args) = self.wrap(program, args);
+        self.inner.output(program, &args)
+    }
+
+    fn interactive(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+        let (program, args) = self.wrap(program, args);
+        self.inner.interactive(program, &args)
+    }
+
+    fn stream(
+        &self,
+        program: &str,
+        args: &[&str],
+        on_line: &(dyn Fn(Stream, &str) + Sync),
+    ) -> Result<CommandOutput> {
+        let (program, args) = self.wrap(program, args);
+        self.inner.stream(program, &args, on_line)
+    }
+}
+
+#[cfg(test)]
+pub use scripted::ScriptedRunner;
+
+#[cfg(test)]
+mod scripted {
+    use std::cell::RefCell;
+
+    use anyhow::{bail, Result};
+
+    use super::{CommandOutput, CommandRunner, Stream};
+
+    /// A fake runner that answers from canned responses and records every
+    /// invocation as `program arg1 arg2 …`.
+    #[derive(Default)]
+    pub struct ScriptedRunner {
+        responses: RefCell<Vec<(String, CommandOutput)>>,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl ScriptedRunner {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Answers `command` (program and args joined by spaces) with the
+        /// given exit code and stdout. Later registrations win.
+        pub fn on(&self, command: &str, code: i32, stdout: &str) -> &Self {
+            self.responses.borrow_mut().push((
+                command.to_owned(),
+                CommandOutput {
+                    code: Some(code),
+                    stdout: stdout.to_owned(),
+                    stderr: String::new(),
+                },
+            ));
+            self
+        }
+
+        pub fn calls(&self) -> Vec<String> {
+            self.calls.borrow().clone()
+        }
+
+        fn respond(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+            let line = std::iter::once(program)
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.calls.borrow_mut().push(line.clone());
+            match self
+                .responses
+                .borrow()
+                .iter()
+                .rev()
+                .find(|(command, _)| *command == line)
+            {
+                Some((_, output)) => Ok(output.clone()),
+                None => bail!("unexpected command `{line}`"),
+            }
+        }
+    }
+
+    impl CommandRunner for ScriptedRunner {
+        fn output(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+            self.respond(program, args)
+        }
+
+        fn interactive(&self, program: &str, args: &[&str]) -> Result<CommandOutput> {
+            self.respond(program, args)
+        }
+
+        fn stream(
+            &self,
+            program: &str,
+            args: &[&str],
+            on_line: &(dyn Fn(Stream, &str) + Sync),
+        ) -> Result<CommandOutput> {
+            let output = self.respond(program, args)?;
+            for line in output.stdout.lines() {
+                on_line(Stream::Stdout, line);
+            }
+            Ok(output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn as_user_wraps_commands_in_sudo() {
+        let runner = ScriptedRunner::new();
+        runner.on("git config --global user.name", 0, "Test\n").on(
+            "sudo -u test -H -- git config --global user.name",
+            0,
+            "Test\n",
+        );
+
+        AsUser::new(&runner, None)
+            .output("git", &["config", "--global", "user.name"])
+            .unwrap();
+        AsUser::new(&runner, Some("test"))
+            .output("git", &["config", "--global", "user.name"])
+            .unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            [
+                "git config --global user.name",
+                "sudo -u test -H -- git config --global user.name"
+            ]
+        );
+    }
+}
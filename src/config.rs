@@ -1,125 +1,1212 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs,
     path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use schemars::JsonSchema;
 use serde::Deserialize;
+use toml::{Table, Value};
 
-#[derive(Debug, Deserialize)]
+use crate::{
+    template::{self, Vars},
+    util::{self, resolve_path, sha256_hex},
+};
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Steps left out of a run unless requested with `--steps`.
+    #[serde(default)]
+    pub disabled_steps: Vec<String>,
+    /// Machine-wide settings.
     pub system: SystemConfig,
+    /// Packages installed with `brew bundle`.
     #[serde(default)]
     pub homebrew: HomebrewConfig,
+    /// Settings for the primary user's account.
     pub user: UserConfig,
 }
 
 #[derive(Debug)]
 pub struct ConfigBundle {
     pub config: Config,
-    #[allow(dead_code)]
     pub path: PathBuf,
     pub root: PathBuf,
+    /// Every file that contributed to `config`, includes first.
+    pub sources: Vec<PathBuf>,
+    /// Profiles merged onto the base config, in the order applied.
+    pub profiles: Vec<String>,
+    /// SHA-256 over all sources, selected profiles and the rendered config,
+    /// used to invalidate run state.
+    pub hash: String,
+}
+
+/// Loads `path` and everything it pulls in through `include`, applies the
+/// selected profiles, then expands `{{ var }}` placeholders.
+///
+/// Included files form the base that the including file is merged onto:
+/// tables merge key by key, arrays concatenate without duplicates, and any
+/// other value from the later file wins. Profiles merge onto the result the
+/// same way. `profile` picks one by name; otherwise every profile whose
+/// `match` fits this machine is applied, in name order.
+pub fn load_config(path: &Path, profile: Option<&str>) -> Result<ConfigBundle> {
+    load_for_host(path, profile, &Host::current())
 }
 
-pub fn load_config(path: &Path) -> Result<ConfigBundle> {
-    let data =
-        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
-    let config: Config =
-        toml::from_str(&data).with_context(|| format!("Invalid TOML in {}", path.display()))?;
-    let root = path
-        .parent()
-        .map(|p| p.to_owned())
-        .unwrap_or_else(|| Path::new(".").to_owned());
+fn load_for_host(path: &Path, profile: Option<&str>, host: &Host) -> Result<ConfigBundle> {
+    let raw = load_raw_for_host(path, profile, host)?;
+    let config = raw.deserialize().map_err(|err| {
+        anyhow::anyhow!(
+            "Invalid configuration in {}: `{}`: {} (run `setup-my-mac validate` for details)",
+            path.display(),
+            err.path(),
+            err.inner().message()
+        )
+    })?;
+    raw.into_bundle(config)
+}
 
-    Ok(ConfigBundle {
-        config,
+/// A config with includes, profiles and templates resolved, before it is
+/// checked against [`Config`].
+#[derive(Debug)]
+pub struct RawConfig {
+    pub table: Table,
+    /// Each source file and its contents, includes first.
+    pub sources: Vec<(PathBuf, String)>,
+    pub profiles: Vec<String>,
+    path: PathBuf,
+    dotfile_roots: HashMap<String, PathBuf>,
+}
+
+pub fn load_raw(path: &Path, profile: Option<&str>) -> Result<RawConfig> {
+    load_raw_for_host(path, profile, &Host::current())
+}
+
+fn load_raw_for_host(path: &Path, profile: Option<&str>, host: &Host) -> Result<RawConfig> {
+    let mut loader = Loader::default();
+    let Value::Table(mut table) = loader.load(path)? else {
+        unreachable!("config files parse as tables");
+    };
+
+    let profiles = match table.remove("profiles") {
+        None => Table::new(),
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => bail!("`profiles` must be a table of named profiles"),
+    };
+    // Profiles match the account the run targets. Under sudo the invoking
+    // user already is that account; as plain root it is the configured one.
+    let target = match host.user.as_str() {
+        "root" => host.for_config(&table),
+        _ => host.clone(),
+    };
+    let selected = select_profiles(&profiles, profile, &target)?;
+    for name in &selected {
+        let Some(Value::Table(overlay)) = profiles.get(name) else {
+            bail!("profile `{name}` must be a table");
+        };
+        let mut overlay = overlay.clone();
+        overlay.remove("match");
+        for dotfile in dotfile_names(&overlay) {
+            if let Some(root) = loader
+                .dotfile_roots
+                .get(&(Some(name.clone()), dotfile.clone()))
+            {
+                let root = root.clone();
+                loader.dotfile_roots.insert((None, dotfile), root);
+            }
+        }
+        merge_overlay(&mut table, overlay).with_context(|| format!("in profile `{name}`"))?;
+    }
+
+    let mut vars = host.for_config(&table).vars();
+    match table.remove("vars") {
+        None => {}
+        Some(Value::Table(defined)) => vars.extend_from_table(defined)?,
+        Some(_) => bail!("`vars` must be a table"),
+    }
+    template::render_table(&mut table, &vars)?;
+
+    Ok(RawConfig {
+        table,
+        sources: loader.sources.into_iter().zip(loader.contents).collect(),
+        profiles: selected,
         path: path.to_owned(),
-        root,
+        dotfile_roots: loader
+            .dotfile_roots
+            .into_iter()
+            .filter_map(|((profile, name), root)| profile.is_none().then_some((name, root)))
+            .collect(),
     })
 }
 
-#[derive(Debug, Deserialize)]
+impl RawConfig {
+    /// Deserializes the merged table, reporting the key path of the first
+    /// problem.
+    pub fn deserialize(&self) -> Result<Config, serde_path_to_error::Error<toml::de::Error>> {
+        serde_path_to_error::deserialize(Value::Table(self.table.clone()))
+    }
+
+    /// Directory relative dotfile paths in entry `name` resolve against.
+    pub fn dotfile_root(&self, name: &str) -> &Path {
+        self.dotfile_roots
+            .get(name)
+            .map(PathBuf::as_path)
+            .unwrap_or_else(|| self.path.parent().unwrap_or(Path::new(".")))
+    }
+
+    pub fn into_bundle(self, mut config: Config) -> Result<ConfigBundle> {
+        for (name, entry) in config.user.dotfiles.iter_mut() {
+            entry.root = self.dotfile_roots.get(name).cloned();
+        }
+
+        if let Some(unknown) = config
+            .disabled_steps
+            .iter()
+            .find(|name| crate::steps::find(name).is_none())
+        {
+            bail!(
+                "unknown step `{unknown}` in disabled_steps (expected one of: {})",
+                crate::steps::names().collect::<Vec<_>>().join(", ")
+            );
+        }
+
+        // scutil takes any LocalHostName and mDNSResponder then renames the
+        // machine, so refuse before any step runs.
+        let system = &config.system;
+        if let Some(name) = system.local_host_name.as_ref().or(system.hostname.as_ref()) {
+            if !crate::validate::is_local_host_name(name) {
+                bail!(
+                    "`{name}` is not a valid LocalHostName (run `setup-my-mac validate` for details)"
+                );
+            }
+        }
+
+        let mut hashed = Vec::new();
+        for (source, data) in &self.sources {
+            hashed.extend_from_slice(source.to_string_lossy().as_bytes());
+            hashed.push(0);
+            hashed.extend_from_slice(data.as_bytes());
+        }
+        for name in &self.profiles {
+            hashed.extend_from_slice(b"\0profile:");
+            hashed.extend_from_slice(name.as_bytes());
+        }
+        // Templates expand the environment and host facts, so the same
+        // sources can still render to a different config.
+        let rendered = toml::to_string(&self.table).context("serializing rendered config")?;
+        hashed.extend_from_slice(b"\0rendered:");
+        hashed.extend_from_slice(rendered.as_bytes());
+
+        Ok(ConfigBundle {
+            config,
+            root: parent_dir(&self.path),
+            path: self.path,
+            hash: sha256_hex(&hashed),
+            sources: self.sources.into_iter().map(|(path, _)| path).collect(),
+            profiles: self.profiles,
+        })
+    }
+}
+
+/// Facts about this machine that profiles match on and templates expand.
+#[derive(Clone)]
+struct Host {
+    hostname: String,
+    user: String,
+    home: String,
+    arch: String,
+}
+
+impl Host {
+    fn current() -> Self {
+        let user = util::invoking_user().unwrap_or_default();
+        let home = match util::current_user() {
+            Some(current) if current == user => shellexpand::tilde("~").into_owned(),
+            _ => util::home_of(&user).unwrap_or_default(),
+        };
+        Host {
+            hostname: util::hostname().unwrap_or_default(),
+            user,
+            home,
+            arch: util::arch().to_owned(),
+        }
+    }
+
+    /// The facts templates expand: `primary_user` and `home_directory` from
+    /// `table` when set literally, since a run as root acts for that account.
+    fn for_config(&self, table: &Table) -> Host {
+        let system = |key| {
+            table
+                .get("system")?
+                .get(key)?
+                .as_str()
+                .filter(|value| !value.contains("{{"))
+                .map(str::to_owned)
+        };
+        Host {
+            hostname: self.hostname.clone(),
+            user: system("primary_user").unwrap_or_else(|| self.user.clone()),
+            home: system("home_directory").unwrap_or_else(|| self.home.clone()),
+            arch: self.arch.clone(),
+        }
+    }
+
+    fn vars(&self) -> Vars {
+        Vars::new([
+            ("hostname", self.hostname.clone()),
+            ("user", self.user.clone()),
+            ("home", self.home.clone()),
+            ("arch", self.arch.clone()),
+        ])
+    }
+}
+
+fn select_profiles(profiles: &Table, requested: Option<&str>, host: &Host) -> Result<Vec<String>> {
+    if let Some(requested) = requested {
+        if !profiles.contains_key(requested) {
+            let known: Vec<_> = profiles.keys().map(String::as_str).collect();
+            if known.is_empty() {
+                bail!("profile `{requested}` not found; the config defines no profiles");
+            }
+            bail!(
+                "profile `{requested}` not found (expected one of: {})",
+                known.join(", ")
+            );
+        }
+        return Ok(vec![requested.to_owned()]);
+    }
+
+    let mut selected = Vec::new();
+    for (name, profile) in profiles {
+        let Some(criteria) = profile.get("match") else {
+            continue;
+        };
+        if profile_matches(name, criteria, host)? {
+            selected.push(name.clone());
+        }
+    }
+    Ok(selected)
+}
+
+/// A profile matches when every criterion it lists holds. `hostname` is a
+/// glob compared case-insensitively with both the full and the short host
+/// name; `primary_user` is compared with the account the run targets.
+fn profile_matches(name: &str, criteria: &Value, host: &Host) -> Result<bool> {
+    let Value::Table(criteria) = criteria else {
+        bail!("`profiles.{name}.match` must be a table");
+    };
+
+    let mut matched = false;
+    for (key, value) in criteria {
+        let Value::String(expected) = value else {
+            bail!("`profiles.{name}.match.{key}` must be a string");
+        };
+        let hit = match key.as_str() {
+            "hostname" => {
+                let pattern = glob::Pattern::new(&expected.to_lowercase()).with_context(|| {
+                    format!("invalid pattern in `profiles.{name}.match.hostname`")
+                })?;
+                let full = host.hostname.to_lowercase();
+                let short = full.split('.').next().unwrap_or_default();
+                pattern.matches(&full) || pattern.matches(short)
+            }
+            "primary_user" => host.user == *expected,
+            other => bail!(
+                "unknown key `{other}` in `profiles.{name}.match` (expected hostname or primary_user)"
+            ),
+        };
+        if !hit {
+            return Ok(false);
+        }
+        matched = true;
+    }
+    Ok(matched)
+}
+
+fn dotfile_names(table: &Table) -> Vec<String> {
+    match table.get("user").and_then(|user| user.get("dotfiles")) {
+        Some(Value::Table(dotfiles)) => dotfiles.keys().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Default)]
+struct Loader {
+    sources: Vec<PathBuf>,
+    contents: Vec<String>,
+    /// Directory of the file that last defined each dotfile, keyed by the
+    /// profile it belongs to (if any), so relative sources resolve against
+    /// the file they were written in.
+    dotfile_roots: HashMap<(Option<String>, String), PathBuf>,
+    /// Files currently being loaded, to reject include cycles.
+    stack: Vec<PathBuf>,
+}
+
+impl Loader {
+    fn load(&mut self, path: &Path) -> Result<Value> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if self.stack.contains(&canonical) {
+            bail!("{} includes itself", path.display());
+        }
+
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut table: Table =
+            toml::from_str(&data).with_context(|| format!("Invalid TOML in {}", path.display()))?;
+        let root = parent_dir(path);
+
+        self.stack.push(canonical);
+        let mut merged = Table::new();
+        for include in include_paths(&mut table, &root, path)? {
+            let included = self
+                .load(&include)
+                .with_context(|| format!("included from {}", path.display()))?;
+            if let Value::Table(included) = included {
+                merge_tables(&mut merged, included);
+            }
+        }
+        self.stack.pop();
+
+        for name in dotfile_names(&table) {
+            self.dotfile_roots.insert((None, name), root.clone());
+        }
+        if let Some(Value::Table(profiles)) = table.get("profiles") {
+            for (profile, overlay) in profiles {
+                if let Value::Table(overlay) = overlay {
+                    for name in dotfile_names(overlay) {
+                        self.dotfile_roots
+                            .insert((Some(profile.clone()), name), root.clone());
+                    }
+                }
+            }
+        }
+        merge_overlay(&mut merged, table).with_context(|| format!("in {}", path.display()))?;
+
+        self.sources.push(path.to_owned());
+        self.contents.push(data);
+        Ok(Value::Table(merged))
+    }
+}
+
+/// Removes the `include` key from `table` and expands it into file paths.
+fn include_paths(table: &mut Table, root: &Path, path: &Path) -> Result<Vec<PathBuf>> {
+    let patterns = match table.remove("include") {
+        None => return Ok(Vec::new()),
+        Some(Value::Array(patterns)) => patterns,
+        Some(Value::String(pattern)) => vec![Value::String(pattern)],
+        Some(_) => bail!("`include` in {} must be a list of paths", path.display()),
+    };
+
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let Value::String(pattern) = pattern else {
+            bail!("`include` in {} must be a list of paths", path.display());
+        };
+        let resolved = resolve_path(&pattern, root)?;
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(resolved);
+            continue;
+        }
+
+        let resolved = resolved.to_string_lossy().into_owned();
+        let mut matches = glob::glob(&resolved)
+            .with_context(|| format!("Invalid include pattern {pattern} in {}", path.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to expand {pattern}"))?;
+        matches.sort();
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+/// Merges a file onto what it includes, or a profile onto the config.
+/// Arrays named in the overlay's `replace` list, as dotted key paths,
+/// replace the earlier array instead of extending it.
+fn merge_overlay(base: &mut Table, mut overlay: Table) -> Result<()> {
+    let paths = match overlay.remove("replace") {
+        None => Vec::new(),
+        Some(Value::Array(paths)) => paths,
+        Some(_) => bail!("`replace` must be a list of dotted key paths"),
+    };
+    for path in paths {
+        let Value::String(path) = path else {
+            bail!("`replace` must be a list of dotted key paths");
+        };
+        remove_path(base, &path);
+    }
+    merge_tables(base, overlay);
+    Ok(())
+}
+
+fn remove_path(table: &mut Table, path: &str) {
+    match path.split_once('.') {
+        Some((key, rest)) => {
+            if let Some(Value::Table(child)) = table.get_mut(key) {
+                remove_path(child, rest);
+            }
+        }
+        None => {
+            table.remove(path);
+        }
+    }
+}
+
+fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge_tables(base, overlay),
+            (Some(Value::Array(base)), Value::Array(overlay)) => {
+                for item in overlay {
+                    if !base.contains(&item) {
+                        base.push(item);
+                    }
+                }
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    path.parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .map(|parent| parent.to_owned())
+        .unwrap_or_else(|| Path::new(".").to_owned())
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct SystemConfig {
-    #[allow(dead_code)]
+    /// Home directory of the primary user, e.g. `/Users/you`.
     pub home_directory: PathBuf,
-    #[allow(dead_code)]
+    /// Short name of the account being set up.
     pub primary_user: String,
+    /// IANA timezone name, e.g. `America/Los_Angeles`.
     pub timezone: Option<String>,
+    /// Name for this Mac, used for the computer name, local hostname and
+    /// hostname unless they are set separately.
+    pub hostname: Option<String>,
+    /// Name shown in Finder and for sharing; defaults to `hostname`.
+    pub computer_name: Option<String>,
+    /// Bonjour name, as in `<name>.local`; defaults to `hostname`. Letters,
+    /// digits and hyphens only.
+    pub local_host_name: Option<String>,
+    /// Name the shell and network tools report; defaults to `hostname`.
+    pub host_name: Option<String>,
+    /// Allow Touch ID to authorize `sudo`.
     #[serde(default)]
     pub touch_id_sudo: bool,
+    /// Trackpad preferences; unset keys are left alone.
     #[serde(default)]
     pub trackpad: TrackpadConfig,
+    /// Any other preferences, written with `defaults write`.
+    #[serde(default)]
+    pub defaults: Vec<DefaultsEntry>,
+    /// Dock preferences; unset keys are left alone.
+    pub dock: Option<DockConfig>,
+    /// Finder preferences; unset keys are left alone.
+    #[serde(default)]
+    pub finder: FinderConfig,
+    /// Screenshot preferences; unset keys are left alone.
+    #[serde(default)]
+    pub screenshots: ScreenshotsConfig,
+    /// Keyboard preferences and modifier remapping; unset keys are left alone.
+    #[serde(default)]
+    pub keyboard: KeyboardConfig,
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DockConfig {
+    /// Hide the Dock until the pointer reaches the screen edge.
+    pub autohide: Option<bool>,
+    /// Icon size in points, 16 to 128.
+    pub tile_size: Option<i64>,
+    /// Enlarge icons under the pointer.
+    pub magnification: Option<bool>,
+    /// Magnified icon size in points, 16 to 128.
+    pub large_size: Option<i64>,
+    /// Screen edge the Dock sits on.
+    pub orientation: Option<DockOrientation>,
+    /// Show recently used apps after the pinned ones.
+    pub show_recents: Option<bool>,
+    /// Animation used when minimizing windows.
+    pub minimize_effect: Option<MinimizeEffect>,
+    /// Apps pinned to the Dock, in order. Replaces the current apps entirely.
+    pub apps: Option<Vec<String>>,
+    /// Folders pinned next to the Trash, in order. Replaces the current ones entirely.
+    pub folders: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DockOrientation {
+    Left,
+    Bottom,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MinimizeEffect {
+    Genie,
+    Scale,
+    Suck,
+}
+
+impl DockOrientation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DockOrientation::Left => "left",
+            DockOrientation::Bottom => "bottom",
+            DockOrientation::Right => "right",
+        }
+    }
+}
+
+impl MinimizeEffect {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MinimizeEffect::Genie => "genie",
+            MinimizeEffect::Scale => "scale",
+            MinimizeEffect::Suck => "suck",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultsEntry {
+    /// Preference domain, e.g. `com.apple.dock`, or `NSGlobalDomain`.
+    pub domain: String,
+    /// Preference key within the domain.
+    pub key: String,
+    /// Use the per-machine preferences (`defaults -currentHost`).
+    #[serde(default)]
+    pub current_host: bool,
+    /// Type of `value`, as stored by `defaults`.
+    #[serde(rename = "type")]
+    pub kind: DefaultsType,
+    /// Desired value. Dates are UTC, e.g. `2024-01-01T00:00:00Z`.
+    #[schemars(with = "serde_json::Value")]
+    pub value: toml::Value,
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultsType {
+    Bool,
+    Int,
+    Float,
+    String,
+    Date,
+    Array,
+    Dict,
+}
+
+impl DefaultsType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DefaultsType::Bool => "bool",
+            DefaultsType::Int => "int",
+            DefaultsType::Float => "float",
+            DefaultsType::String => "string",
+            DefaultsType::Date => "date",
+            DefaultsType::Array => "array",
+            DefaultsType::Dict => "dict",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct TrackpadConfig {
+    /// Tap to click.
     pub clicking: Option<bool>,
+    /// Drag windows with three fingers.
     #[serde(rename = "three_finger_drag")]
     pub three_finger_drag: Option<bool>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FinderConfig {
+    /// Show every file extension.
+    pub show_extensions: Option<bool>,
+    /// Show hidden files.
+    pub show_hidden_files: Option<bool>,
+    /// Show the path bar at the bottom of Finder windows.
+    pub show_path_bar: Option<bool>,
+    /// Show the status bar at the bottom of Finder windows.
+    pub show_status_bar: Option<bool>,
+    /// View style for windows that have none of their own.
+    pub default_view: Option<FinderView>,
+    /// What new Finder windows open: `home`, `desktop`, `documents`,
+    /// `computer`, or the path of a folder.
+    pub new_window_target: Option<String>,
+    /// Stop writing `.DS_Store` files on network volumes.
+    pub disable_network_ds_store: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FinderView {
+    Icon,
+    List,
+    Column,
+    Gallery,
+}
+
+impl FinderView {
+    /// The code Finder stores in `FXPreferredViewStyle`.
+    pub fn code(self) -> &'static str {
+        match self {
+            FinderView::Icon => "icnv",
+            FinderView::List => "Nlsv",
+            FinderView::Column => "clmv",
+            FinderView::Gallery => "glyv",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ScreenshotsConfig {
+    /// Folder screenshots are saved to, e.g. `~/Pictures/Screenshots`.
+    pub location: Option<String>,
+    /// Image format screenshots are saved in.
+    pub format: Option<ScreenshotFormat>,
+    /// Leave out the window shadow when capturing a window.
+    pub disable_shadow: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreenshotFormat {
+    Png,
+    Jpg,
+    Heic,
+    Gif,
+    Pdf,
+    Tiff,
+}
+
+impl ScreenshotFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScreenshotFormat::Png => "png",
+            ScreenshotFormat::Jpg => "jpg",
+            ScreenshotFormat::Heic => "heic",
+            ScreenshotFormat::Gif => "gif",
+            ScreenshotFormat::Pdf => "pdf",
+            ScreenshotFormat::Tiff => "tiff",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KeyboardConfig {
+    /// Delay between repeats while a key is held, in 15 ms units (2 is the
+    /// fastest the System Settings slider offers).
+    pub key_repeat: Option<i64>,
+    /// Delay before a held key starts repeating, in 15 ms units.
+    pub initial_key_repeat: Option<i64>,
+    /// Show the accent picker when a key is held instead of repeating it.
+    pub press_and_hold: Option<bool>,
+    /// Use F1, F2, etc. as standard function keys.
+    pub fn_as_standard: Option<bool>,
+    /// Modifier remapping, e.g. `{ caps_lock = "control" }`. Replaces any
+    /// existing remapping; an empty table removes it.
+    pub remap: Option<BTreeMap<RemapKey, RemapKey>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RemapKey {
+    CapsLock,
+    Escape,
+    Control,
+    Shift,
+    Option,
+    Command,
+    RightControl,
+    RightShift,
+    RightOption,
+    RightCommand,
+}
+
+impl RemapKey {
+    pub const ALL: [RemapKey; 10] = [
+        RemapKey::CapsLock,
+        RemapKey::Escape,
+        RemapKey::Control,
+        RemapKey::Shift,
+        RemapKey::Option,
+        RemapKey::Command,
+        RemapKey::RightControl,
+        RemapKey::RightShift,
+        RemapKey::RightOption,
+        RemapKey::RightCommand,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RemapKey::CapsLock => "caps_lock",
+            RemapKey::Escape => "escape",
+            RemapKey::Control => "control",
+            RemapKey::Shift => "shift",
+            RemapKey::Option => "option",
+            RemapKey::Command => "command",
+            RemapKey::RightControl => "right_control",
+            RemapKey::RightShift => "right_shift",
+            RemapKey::RightOption => "right_option",
+            RemapKey::RightCommand => "right_command",
+        }
+    }
+
+    /// The key's HID usage as `hidutil` expects it: the keyboard page
+    /// (`0x7`) followed by the usage ID.
+    pub fn usage(self) -> u64 {
+        let id = match self {
+            RemapKey::CapsLock => 0x39,
+            RemapKey::Escape => 0x29,
+            RemapKey::Control => 0xE0,
+            RemapKey::Shift => 0xE1,
+            RemapKey::Option => 0xE2,
+            RemapKey::Command => 0xE3,
+            RemapKey::RightControl => 0xE4,
+            RemapKey::RightShift => 0xE5,
+            RemapKey::RightOption => 0xE6,
+            RemapKey::RightCommand => 0xE7,
+        };
+        0x7_0000_0000 | id
+    }
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct HomebrewConfig {
+    /// Run the Homebrew step by default.
     #[serde(default)]
     pub enable: bool,
+    /// Formulae to install.
     #[serde(default)]
     pub brews: Vec<String>,
+    /// Casks to install.
     #[serde(default)]
     pub casks: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct UserConfig {
+    /// Contents of `~/.ssh/config`.
     pub ssh: Option<SshConfig>,
+    /// Files or directories copied into place, keyed by a name of your choice.
     #[serde(default)]
     pub dotfiles: HashMap<String, DotfileEntry>,
+    /// Global git configuration.
     pub git: Option<GitConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct SshConfig {
+    /// Full text written to `~/.ssh/config`.
     pub config: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DotfileEntry {
+    /// File or directory to copy, relative to the config file that defines it.
     pub source: String,
+    /// Where the copy goes, e.g. `~/.zshrc`.
     pub target: String,
+    /// Directory of the config file that defined this entry.
+    #[serde(skip)]
+    pub root: Option<PathBuf>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct GitConfig {
+    /// Run the git step by default.
     #[serde(default)]
     pub enable: bool,
+    /// `user.email`.
     pub user_email: Option<String>,
+    /// `user.name`.
     pub user_name: Option<String>,
+    /// `credential.helper`, e.g. `osxkeychain`.
     pub credential_helper: Option<String>,
+    /// Patterns written to the global ignore file.
     #[serde(default)]
     pub ignores: Vec<String>,
+    /// `[init]` settings.
     pub init: Option<GitInit>,
+    /// `[merge]` settings.
     pub merge: Option<GitMerge>,
+    /// `[pull]` settings.
     pub pull: Option<GitPull>,
+    /// `[push]` settings.
     pub push: Option<GitPush>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct GitInit {
+    /// `init.defaultBranch`.
     pub default_branch: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct GitMerge {
+    /// `merge.conflictStyle`.
+    #[schemars(extend("enum" = ["merge", "diff3", "zdiff3"]))]
     pub conflictstyle: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct GitPull {
+    /// `pull.rebase`.
     pub rebase: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct GitPush {
+    /// `push.autoSetupRemote`.
     pub auto_setup_remote: Option<bool>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+[system]
+home_directory = "/Users/test"
+primary_user = "test"
+timezone = "UTC"
+
+[homebrew]
+enable = true
+brews = ["git", "jq"]
+
+[user.dotfiles.zsh]
+source = "dotfiles/zshrc"
+target = "~/.zshrc"
+"#;
+
+    #[test]
+    fn includes_merge_under_the_including_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("shared/team")).unwrap();
+        fs::write(dir.path().join("shared/base.toml"), BASE).unwrap();
+        fs::write(
+            dir.path().join("shared/team/b.toml"),
+            "[homebrew]\nbrews = [\"ripgrep\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("shared/team/a.toml"),
+            "[homebrew]\ncasks = [\"firefox\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("config.toml"),
+            r#"
+include = ["shared/base.toml", "shared/team/*.toml"]
+
+[system]
+timezone = "Europe/Berlin"
+
+[homebrew]
+brews = ["jq", "fd"]
+
+[user.dotfiles.vim]
+source = "vimrc"
+target = "~/.vimrc"
+"#,
+        )
+        .unwrap();
+
+        let bundle = load_config(&dir.path().join("config.toml"), None).unwrap();
+        let cfg = &bundle.config;
+        assert_eq!(cfg.system.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(cfg.system.primary_user, "test");
+        assert_eq!(cfg.homebrew.brews, ["git", "jq", "ripgrep", "fd"]);
+        assert_eq!(cfg.homebrew.casks, ["firefox"]);
+        assert_eq!(
+            cfg.user.dotfiles["zsh"].root.as_deref(),
+            Some(dir.path().join("shared").as_path())
+        );
+        assert_eq!(cfg.user.dotfiles["vim"].root.as_deref(), Some(dir.path()));
+        assert_eq!(bundle.sources.len(), 4);
+        assert_eq!(bundle.sources.last(), Some(&dir.path().join("config.toml")));
+    }
+
+    #[test]
+    fn replace_swaps_arrays_instead_of_extending_them() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("base.toml"),
+            format!("{BASE}\n[system.dock]\napps = [\"/Applications/Safari.app\"]\n"),
+        )
+        .unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+include = ["base.toml"]
+replace = ["homebrew.brews"]
+
+[homebrew]
+brews = ["fd"]
+
+[profiles.work]
+replace = ["system.dock.apps"]
+system.dock.apps = ["/Applications/Slack.app"]
+"#,
+        )
+        .unwrap();
+
+        let cfg = load_for_host(&path, None, &host("mini", "me"))
+            .unwrap()
+            .config;
+        assert_eq!(cfg.homebrew.brews, ["fd"]);
+        let apps = cfg.system.dock.unwrap().apps.unwrap();
+        assert_eq!(apps, ["/Applications/Safari.app"]);
+
+        let cfg = load_for_host(&path, Some("work"), &host("mini", "me"))
+            .unwrap()
+            .config;
+        let apps = cfg.system.dock.unwrap().apps.unwrap();
+        assert_eq!(apps, ["/Applications/Slack.app"]);
+    }
+
+    #[test]
+    fn include_cycles_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.toml"), "include = [\"b.toml\"]\n").unwrap();
+        fs::write(dir.path().join("b.toml"), "include = [\"a.toml\"]\n").unwrap();
+
+        let err = load_config(&dir.path().join("a.toml"), None).unwrap_err();
+        assert!(format!("{err:#}").contains("includes itself"), "{err:#}");
+    }
+
+    #[test]
+    fn hash_covers_included_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.toml");
+        fs::write(dir.path().join("base.toml"), BASE).unwrap();
+        fs::write(&config, "include = [\"base.toml\"]\n").unwrap();
+        let before = load_config(&config, None).unwrap().hash;
+
+        fs::write(dir.path().join("base.toml"), BASE.replace("UTC", "GMT")).unwrap();
+        assert_ne!(load_config(&config, None).unwrap().hash, before);
+    }
+
+    #[test]
+    fn hash_covers_expanded_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            format!("{BASE}\n[user.git]\nuser_name = \"{{{{ hostname }}}}\"\n"),
+        )
+        .unwrap();
+
+        let mini = load_for_host(&path, None, &host("mini", "me"))
+            .unwrap()
+            .hash;
+        let studio = load_for_host(&path, None, &host("studio", "me"))
+            .unwrap()
+            .hash;
+        assert_ne!(mini, studio);
+    }
+
+    const PROFILES: &str = r#"
+[user.git]
+enable = true
+user_email = "me@example.com"
+
+[profiles.work]
+match = { hostname = "acme-*" }
+disabled_steps = ["dotfiles"]
+
+[profiles.work.homebrew]
+casks = ["slack"]
+
+[profiles.work.user.git]
+user_email = "me@acme.example"
+
+[profiles.home]
+match = { primary_user = "me" }
+
+[profiles.home.homebrew]
+casks = ["steam"]
+"#;
+
+    fn host(hostname: &str, user: &str) -> Host {
+        Host {
+            hostname: hostname.to_owned(),
+            user: user.to_owned(),
+            home: format!("/Users/{user}"),
+            arch: "arm64".to_owned(),
+        }
+    }
+
+    fn write_profiles(dir: &Path) -> PathBuf {
+        let path = dir.join("config.toml");
+        fs::write(&path, format!("{BASE}\n{PROFILES}")).unwrap();
+        path
+    }
+
+    #[test]
+    fn profiles_overlay_the_base_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_profiles(dir.path());
+
+        let bundle = load_for_host(&path, Some("work"), &host("laptop", "me")).unwrap();
+        let cfg = &bundle.config;
+        assert_eq!(bundle.profiles, ["work"]);
+        assert_eq!(cfg.disabled_steps, ["dotfiles"]);
+        assert_eq!(cfg.homebrew.brews, ["git", "jq"]);
+        assert_eq!(cfg.homebrew.casks, ["slack"]);
+        assert_eq!(
+            cfg.user.git.as_ref().unwrap().user_email.as_deref(),
+            Some("me@acme.example")
+        );
+    }
+
+    #[test]
+    fn profiles_are_matched_on_hostname_and_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_profiles(dir.path());
+
+        let both = load_for_host(&path, None, &host("ACME-1234.corp.example", "me")).unwrap();
+        assert_eq!(both.profiles, ["home", "work"]);
+        assert_eq!(both.config.homebrew.casks, ["steam", "slack"]);
+
+        let neither = load_for_host(&path, None, &host("mini", "guest")).unwrap();
+        assert!(neither.profiles.is_empty());
+        assert!(neither.config.homebrew.casks.is_empty());
+        assert_ne!(neither.hash, both.hash);
+    }
+
+    #[test]
+    fn profiles_match_the_target_account_when_run_as_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let base = BASE.replace("primary_user = \"test\"", "primary_user = \"me\"");
+        fs::write(&path, format!("{base}\n{PROFILES}")).unwrap();
+
+        let root = load_for_host(&path, None, &host("mini", "root")).unwrap();
+        assert_eq!(root.profiles, ["home"]);
+
+        let guest = load_for_host(&path, None, &host("mini", "guest")).unwrap();
+        assert!(guest.profiles.is_empty());
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_profiles(dir.path());
+
+        let err = load_for_host(&path, Some("travel"), &host("mini", "me")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "profile `travel` not found (expected one of: home, work)"
+        );
+    }
+
+    #[test]
+    fn templates_expand_after_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            format!(
+                r#"{BASE}
+[vars]
+domain = "example.com"
+
+[user.ssh]
+config = "Host *\n  IdentityFile {{{{ home }}}}/.ssh/id_{{{{ hostname }}}}\n"
+
+[user.git]
+user_email = "{{{{ user }}}}@{{{{ domain }}}}"
+
+[profiles.work.vars]
+domain = "acme.example"
+"#
+            ),
+        )
+        .unwrap();
+
+        // Run as root, `user` and `home` still name the configured account.
+        let cfg = load_for_host(&path, None, &host("mini", "root"))
+            .unwrap()
+            .config;
+        assert_eq!(
+            cfg.user.ssh.unwrap().config,
+            "Host *\n  IdentityFile /Users/test/.ssh/id_mini\n"
+        );
+        assert_eq!(
+            cfg.user.git.unwrap().user_email.as_deref(),
+            Some("test@example.com")
+        );
+
+        let work = load_for_host(&path, Some("work"), &host("mini", "me")).unwrap();
+        assert_eq!(
+            work.config.user.git.unwrap().user_email.as_deref(),
+            Some("test@acme.example")
+        );
+
+        fs::write(
+            &path,
+            format!("{BASE}\n[user.git]\nuser_name = \"{{{{ nope }}}}\"\n"),
+        )
+        .unwrap();
+        let err = load_for_host(&path, None, &host("mini", "me")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "undefined variable `nope` in `user.git.user_name`"
+        );
+    }
+
+    #[test]
+    fn invalid_local_host_names_are_refused_at_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let system = "[system]\nhome_directory = \"/Users/test\"\nprimary_user = \"test\"\n";
+        fs::write(
+            &path,
+            format!("{system}hostname = \"Jane's Mac\"\n[user]\n"),
+        )
+        .unwrap();
+        let err = load_for_host(&path, None, &host("mini", "me")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`Jane's Mac` is not a valid LocalHostName (run `setup-my-mac validate` for details)"
+        );
+
+        fs::write(
+            &path,
+            format!("{system}hostname = \"Jane's Mac\"\nlocal_host_name = \"janes-mac\"\n[user]\n"),
+        )
+        .unwrap();
+        load_for_host(&path, None, &host("mini", "me")).unwrap();
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Cursor;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{command::CommandRunner, config::DefaultsType};
+
+const DEFAULTS: &str = "/usr/bin/defaults";
+
+/// A preference value, typed the way `defaults` stores it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum DefaultsValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    /// A UTC instant as RFC 3339, e.g. `2024-01-01T00:00:00Z`.
+    Date(String),
+    /// Raw bytes; only ever read back from the machine.
+    Data(Vec<u8>),
+    Array(Vec<DefaultsValue>),
+    Dict(BTreeMap<String, DefaultsValue>),
+}
+
+impl DefaultsValue {
+    /// Converts a config `value` to the declared `type`.
+    pub fn from_config(kind: DefaultsType, value: &toml::Value) -> Result<Self> {
+        use toml::Value as Toml;
+        Ok(match (kind, value) {
+            (DefaultsType::Bool, Toml::Boolean(value)) => Self::Bool(*value),
+            (DefaultsType::Int, Toml::Integer(value)) => Self::Int(*value),
+            (DefaultsType::Float, Toml::Float(value)) => Self::Float(*value),
+            (DefaultsType::Float, Toml::Integer(value)) => Self::Float(*value as f64),
+            (DefaultsType::String, Toml::String(value)) => Self::String(value.clone()),
+            (DefaultsType::Date, Toml::Datetime(value)) => Self::date(&value.to_string())?,
+            (DefaultsType::Date, Toml::String(value)) => Self::date(value)?,
+            (DefaultsType::Array, Toml::Array(_)) | (DefaultsType::Dict, Toml::Table(_)) => {
+                Self::infer(value)?
+            }
+            _ => bail!(
+                "expected {} value, found {}",
+                kind.as_str(),
+                value.type_str()
+            ),
+        })
+    }
+
+    /// Converts nested array and dict members by their TOML type.
+    fn infer(value: &toml::Value) -> Result<Self> {
+        use toml::Value as Toml;
+        Ok(match value {
+            Toml::Boolean(value) => Self::Bool(*value),
+            Toml::Integer(value) => Self::Int(*value),
+            Toml::Float(value) => Self::Float(*value),
+            Toml::String(value) => Self::String(value.clone()),
+            Toml::Datetime(value) => Self::date(&value.to_string())?,
+            Toml::Array(items) => {
+                Self::Array(items.iter().map(Self::infer).collect::<Result<_>>()?)
+            }
+            Toml::Table(table) => Self::Dict(
+                table
+                    .iter()
+                    .map(|(key, value)| Ok((key.clone(), Self::infer(value)?)))
+                    .collect::<Result<_>>()?,
+            ),
+        })
+    }
+
+    fn date(value: &str) -> Result<Self> {
+        let time = humantime::parse_rfc3339_weak(value)
+            .map_err(|_| anyhow!("`{value}` is not a UTC date like 2024-01-01T00:00:00Z"))?;
+        Ok(Self::Date(
+            humantime::format_rfc3339_seconds(time).to_string(),
+        ))
+    }
+
+    fn from_plist(value: plist::Value) -> Self {
+        match value {
+            plist::Value::Boolean(value) => Self::Bool(value),
+            plist::Value::Integer(value) => Self::Int(
+                value
+                    .as_signed()
+                    .or_else(|| value.as_unsigned().map(|value| value as i64))
+                    .unwrap_or_default(),
+            ),
+            plist::Value::Real(value) => Self::Float(value),
+            plist::Value::String(value) => Self::String(value),
+            plist::Value::Date(value) => {
+                Self::Date(humantime::format_rfc3339_seconds(SystemTime::from(value)).to_string())
+            }
+            plist::Value::Data(value) => Self::Data(value),
+            plist::Value::Array(items) => {
+                Self::Array(items.into_iter().map(Self::from_plist).collect())
+            }
+            plist::Value::Dictionary(dict) => Self::Dict(
+                dict.into_iter()
+                    .map(|(key, value)| (key, Self::from_plist(value)))
+                    .collect(),
+            ),
+            plist::Value::Uid(uid) => Self::Int(uid.get() as i64),
+            // `plist::Value` is non-exhaustive.
+            _ => Self::Data(Vec::new()),
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Bool(_) => "bool",
+            Self::Int(_) => "int",
+            Self::Float(_) => "float",
+            Self::String(_) => "string",
+            Self::Date(_) => "date",
+            Self::Data(_) => "data",
+            Self::Array(_) => "array",
+            Self::Dict(_) => "dict",
+        }
+    }
+
+    /// Arguments after `defaults write <domain> <key>`. Scalars use the
+    /// typed flags; containers and the rest are passed as XML plist, which
+    /// `defaults` parses without losing nested types.
+    fn write_args(&self) -> Vec<String> {
+        let flag = |flag: &str, value: String| vec![flag.to_owned(), value];
+        match self {
+            Self::Bool(value) => flag("-bool", if *value { "TRUE" } else { "FALSE" }.into()),
+            Self::Int(value) => flag("-int", value.to_string()),
+            Self::Float(value) => flag("-float", value.to_string()),
+            Self::String(value) => flag("-string", value.clone()),
+            _ => {
+                let mut xml = String::new();
+                self.write_xml(&mut xml);
+                vec![xml]
+            }
+        }
+    }
+
+    fn write_xml(&self, out: &mut String) {
+        match self {
+            Self::Bool(true) => out.push_str("<true/>"),
+            Self::Bool(false) => out.push_str("<false/>"),
+            Self::Int(value) => out.push_str(&format!("<integer>{value}</integer>")),
+            Self::Float(value) => out.push_str(&format!("<real>{value}</real>")),
+            Self::String(value) => out.push_str(&format!("<string>{}</string>", escape(value))),
+            Self::Date(value) => out.push_str(&format!("<date>{value}</date>")),
+            Self::Data(value) => out.push_str(&format!("<data>{}</data>", base64(value))),
+            Self::Array(items) => {
+                out.push_str("<array>");
+                for item in items {
+                    item.write_xml(out);
+                }
+                out.push_str("</array>");
+            }
+            Self::Dict(dict) => {
+                out.push_str("<dict>");
+                for (key, value) in dict {
+                    out.push_str(&format!("<key>{}</key>", escape(key)));
+                    value.write_xml(out);
+                }
+                out.push_str("</dict>");
+            }
+        }
+    }
+}
+
+impl fmt::Display for DefaultsValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{value}"),
+            Self::Int(value) => write!(f, "{value}"),
+            Self::Float(value) => write!(f, "{value}"),
+            Self::String(value) => write!(f, "{value:?}"),
+            Self::Date(value) => write!(f, "{value}"),
+            Self::Data(value) => write!(f, "<{} bytes>", value.len()),
+            Self::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Self::Dict(dict) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in dict.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, " {key} = {value}")?;
+                }
+                write!(f, " }}")
+            }
+        }
+    }
+}
+
+/// A preference key; `current_host` selects the per-machine domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key<'a> {
+    pub domain: &'a str,
+    pub key: &'a str,
+    pub current_host: bool,
+}
+
+impl Key<'_> {
+    /// `defaults` arguments up to and including `verb`.
+    fn args(&self, verb: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.current_host {
+            args.push("-currentHost".to_owned());
+        }
+        args.extend([verb.to_owned(), self.domain.to_owned()]);
+        args
+    }
+}
+
+impl fmt::Display for Key<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.current_host {
+            write!(f, "-currentHost ")?;
+        }
+        write!(f, "{} {}", self.domain, self.key)
+    }
+}
+
+/// Reads preferences, exporting each domain once.
+pub struct Reader<'a> {
+    cmd: &'a dyn CommandRunner,
+    domains: HashMap<(bool, String), BTreeMap<String, DefaultsValue>>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(cmd: &'a dyn CommandRunner) -> Self {
+        Reader {
+            cmd,
+            domains: HashMap::new(),
+        }
+    }
+
+    /// The current value of `key`, or `None` when it is unset.
+    pub fn read(&mut self, key: Key<'_>) -> Result<Option<DefaultsValue>> {
+        let id = (key.current_host, key.domain.to_owned());
+        if !self.domains.contains_key(&id) {
+            let domain = export(self.cmd, key)?;
+            self.domains.insert(id.clone(), domain);
+        }
+        Ok(self.domains[&id].get(key.key).cloned())
+    }
+}
+
+/// Every key in `key.domain`, parsed from `defaults export`. Domains that
+/// do not exist yet read as empty.
+fn export(cmd: &dyn CommandRunner, key: Key<'_>) -> Result<BTreeMap<String, DefaultsValue>> {
+    let mut args = key.args("export");
+    args.push("-".to_owned());
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = cmd
+        .output(DEFAULTS, &args)
+        .with_context(|| format!("defaults export {}", key.domain))?;
+    if !output.success() || output.stdout.trim().is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let value = plist::Value::from_reader(Cursor::new(output.stdout.as_bytes()))
+        .with_context(|| format!("parsing `defaults export {}`", key.domain))?;
+    match DefaultsValue::from_plist(value) {
+        DefaultsValue::Dict(dict) => Ok(dict),
+        other => bail!(
+            "`defaults export {}` returned {}, not a dict",
+            key.domain,
+            other.type_name()
+        ),
+    }
+}
+
+pub fn write(cmd: &dyn CommandRunner, key: Key<'_>, value: &DefaultsValue) -> Result<()> {
+    let mut args = key.args("write");
+    args.push(key.key.to_owned());
+    args.extend(value.write_args());
+    run(cmd, &args).with_context(|| format!("defaults write {key}"))
+}
+
+pub fn delete(cmd: &dyn CommandRunner, key: Key<'_>) -> Result<()> {
+    let mut args = key.args("delete");
+    args.push(key.key.to_owned());
+    run(cmd, &args).with_context(|| format!("defaults delete {key}"))
+}
+
+/// The app that has to restart before a change to `key` in `domain` shows up.
+pub fn owning_app(domain: &str, key: &str) -> Option<&'static str> {
+    match (domain, key) {
+        ("com.apple.dock", _) => Some("Dock"),
+        ("com.apple.finder" | "com.apple.desktopservices", _)
+        | ("NSGlobalDomain", "AppleShowAllExtensions") => Some("Finder"),
+        ("com.apple.screencapture", _) => Some("SystemUIServer"),
+        _ => None,
+    }
+}
+
+/// Restarts `app` with `killall`; launchd brings system apps straight back.
+pub fn restart(cmd: &dyn CommandRunner, app: &str) -> Result<()> {
+    let output = cmd
+        .output("/usr/bin/killall", &[app])
+        .with_context(|| format!("restarting {app}"))?;
+    // Exit code 1 means it was not running, so there is nothing to reload.
+    if output.success() || output.code == Some(1) {
+        Ok(())
+    } else {
+        bail!("killall {app} failed: {}", output.stderr.trim())
+    }
+}
+
+fn run(cmd: &dyn CommandRunner, args: &[String]) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = cmd.output(DEFAULTS, &args)?;
+    if output.success() {
+        Ok(())
+    } else {
+        bail!("defaults failed: {}", output.stderr.trim())
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::ScriptedRunner;
+
+    const EXPORT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>autohide</key>
+	<true/>
+	<key>tilesize</key>
+	<integer>48</integer>
+	<key>autohide-delay</key>
+	<real>0.25</real>
+	<key>persistent-others</key>
+	<array>
+		<dict>
+			<key>tile-type</key>
+			<string>directory-tile</string>
+		</dict>
+	</array>
+	<key>installed</key>
+	<date>2024-01-01T00:00:00Z</date>
+</dict>
+</plist>
+"#;
+
+    fn toml(value: &str) -> toml::Value {
+        toml::from_str::<toml::Table>(&format!("value = {value}")).unwrap()["value"].clone()
+    }
+
+    #[test]
+    fn export_output_is_parsed_with_types() {
+        let runner = ScriptedRunner::new();
+        runner.on("/usr/bin/defaults export com.apple.dock -", 0, EXPORT);
+        let mut reader = Reader::new(&runner);
+        let key = |key| Key {
+            domain: "com.apple.dock",
+            key,
+            current_host: false,
+        };
+
+        assert_eq!(
+            reader.read(key("autohide")).unwrap(),
+            Some(DefaultsValue::Bool(true))
+        );
+        assert_eq!(
+            reader.read(key("tilesize")).unwrap(),
+            Some(DefaultsValue::Int(48))
+        );
+        assert_eq!(
+            reader.read(key("autohide-delay")).unwrap(),
+            Some(DefaultsValue::Float(0.25))
+        );
+        assert_eq!(
+            reader.read(key("installed")).unwrap(),
+            Some(DefaultsValue::Date("2024-01-01T00:00:00Z".to_owned()))
+        );
+        assert_eq!(
+            reader.read(key("persistent-others")).unwrap(),
+            Some(
+                DefaultsValue::from_config(
+                    DefaultsType::Array,
+                    &toml(r#"[{ tile-type = "directory-tile" }]"#)
+                )
+                .unwrap()
+            )
+        );
+        assert_eq!(reader.read(key("orientation")).unwrap(), None);
+        assert_eq!(runner.calls().len(), 1, "the domain is exported once");
+    }
+
+    #[test]
+    fn config_values_must_match_their_type() {
+        assert_eq!(
+            DefaultsValue::from_config(DefaultsType::Float, &toml("2")).unwrap(),
+            DefaultsValue::Float(2.0)
+        );
+        assert_eq!(
+            DefaultsValue::from_config(DefaultsType::Date, &toml("2024-01-01T00:00:00Z")).unwrap(),
+            DefaultsValue::Date("2024-01-01T00:00:00Z".to_owned())
+        );
+        let err = DefaultsValue::from_config(DefaultsType::Bool, &toml(r#""yes""#)).unwrap_err();
+        assert_eq!(err.to_string(), "expected bool value, found string");
+    }
+
+    #[test]
+    fn writes_use_typed_flags_or_xml() {
+        let runner = ScriptedRunner::new();
+        runner
+            .on("/usr/bin/defaults -currentHost write NSGlobalDomain com.apple.mouse.tapBehavior -int 1", 0, "")
+            .on(
+                "/usr/bin/defaults write com.example list <array><string>a &amp; b</string><data>AQID</data></array>",
+                0,
+                "",
+            );
+
+        write(
+            &runner,
+            Key {
+                domain: "NSGlobalDomain",
+                key: "com.apple.mouse.tapBehavior",
+                current_host: true,
+            },
+            &DefaultsValue::Int(1),
+        )
+        .unwrap();
+        write(
+            &runner,
+            Key {
+                domain: "com.example",
+                key: "list",
+                current_host: false,
+            },
+            &DefaultsValue::Array(vec![
+                DefaultsValue::String("a & b".to_owned()),
+                DefaultsValue::Data(vec![1, 2, 3]),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(runner.calls().len(), 2);
+    }
+}
@@ -0,0 +1,205 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use console::style;
+use similar::{ChangeTag, TextDiff};
+
+use crate::{steps::Change, util::sha256_hex};
+
+/// Lines of unchanged context shown around each hunk.
+const CONTEXT_LINES: usize = 3;
+
+/// Renders the content change `change` would make, if it replaces a file.
+///
+/// Returns `None` for changes that do not touch file contents and for
+/// copies whose target is already identical.
+pub fn for_change(change: &Change) -> Result<Option<String>> {
+    match change {
+        Change::WriteFile {
+            path,
+            current,
+            contents,
+            ..
+        }
+        | Change::TouchIdSudo {
+            path,
+            current,
+            contents,
+        } => Ok(render(
+            path,
+            current.as_deref().map(str::as_bytes),
+            contents.as_bytes(),
+        )),
+        Change::CopyFile { source, target } => {
+            let new = fs::read(source).with_context(|| format!("reading {}", source.display()))?;
+            let old = match fs::read(target) {
+                Ok(old) => Some(old),
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("reading {}", target.display()));
+                }
+            };
+            Ok(render(target, old.as_deref(), &new))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Produces a colored unified diff of `path` going from `old` to `new`, or
+/// a size and hash summary when either side is binary.
+pub fn render(path: &Path, old: Option<&[u8]>, new: &[u8]) -> Option<String> {
+    if old == Some(new) {
+        return None;
+    }
+
+    let (Some(old_text), Some(new_text)) = (text(old.unwrap_or_default()), text(new)) else {
+        return Some(binary_summary(path, old, new));
+    };
+
+    let diff = TextDiff::from_lines(old_text, new_text);
+    let old_header = match old {
+        Some(_) => format!("a{}", display_path(path)),
+        None => "/dev/null".to_owned(),
+    };
+    let mut out = format!(
+        "{}\n{}\n",
+        style(format!("--- {old_header}")).bold(),
+        style(format!("+++ b{}", display_path(path))).bold()
+    );
+    for group in diff.grouped_ops(CONTEXT_LINES) {
+        let (first, last) = (&group[0], &group[group.len() - 1]);
+        let old_range = first.old_range().start..last.old_range().end;
+        let new_range = first.new_range().start..last.new_range().end;
+        out.push_str(
+            &style(format!(
+                "@@ -{} +{} @@",
+                hunk_range(old_range),
+                hunk_range(new_range)
+            ))
+            .cyan()
+            .to_string(),
+        );
+        out.push('\n');
+        for op in &group {
+            for change in diff.iter_changes(op) {
+                let line = format!("{}{}", change.tag(), change.value().trim_end_matches('\n'));
+                let line = match change.tag() {
+                    ChangeTag::Delete => style(line).red().to_string(),
+                    ChangeTag::Insert => style(line).green().to_string(),
+                    ChangeTag::Equal => line,
+                };
+                out.push_str(&line);
+                out.push('\n');
+                if change.missing_newline() {
+                    out.push_str("\\ No newline at end of file\n");
+                }
+            }
+        }
+    }
+    Some(out)
+}
+
+/// Formats a hunk range the way `diff -u` does: one-based, except that an
+/// empty range points at the line before it.
+fn hunk_range(range: std::ops::Range<usize>) -> String {
+    if range.is_empty() {
+        format!("{},0", range.start)
+    } else {
+        format!("{},{}", range.start + 1, range.len())
+    }
+}
+
+fn text(bytes: &[u8]) -> Option<&str> {
+    if bytes.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(bytes).ok()
+}
+
+fn binary_summary(path: &Path, old: Option<&[u8]>, new: &[u8]) -> String {
+    let describe =
+        |bytes: &[u8]| format!("{} bytes, sha256 {}", bytes.len(), &sha256_hex(bytes)[..12]);
+    let old = old.map_or_else(|| "absent".to_owned(), describe);
+    format!(
+        "{}\n",
+        style(format!(
+            "Binary file {}: {old} -> {}",
+            path.display(),
+            describe(new)
+        ))
+        .bold()
+    )
+}
+
+fn display_path(path: &Path) -> String {
+    let path = path.display().to_string();
+    if path.starts_with('/') {
+        path
+    } else {
+        format!("/{path}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(rendered: Option<String>) -> String {
+        console::strip_ansi_codes(&rendered.expect("diff rendered")).into_owned()
+    }
+
+    #[test]
+    fn text_changes_render_as_unified_diff() {
+        let old = "Host *\n  AddKeysToAgent yes\n  UseKeychain yes\n";
+        let new = "Host *\n  AddKeysToAgent yes\n  IdentityFile ~/.ssh/id_ed25519\n";
+
+        let diff = plain(render(
+            Path::new("/Users/test/.ssh/config"),
+            Some(old.as_bytes()),
+            new.as_bytes(),
+        ));
+        assert_eq!(
+            diff,
+            "--- a/Users/test/.ssh/config\n\
+             +++ b/Users/test/.ssh/config\n\
+             @@ -1,3 +1,3 @@\n \
+             Host *\n   \
+             AddKeysToAgent yes\n\
+             -  UseKeychain yes\n\
+             +  IdentityFile ~/.ssh/id_ed25519\n"
+        );
+    }
+
+    #[test]
+    fn identical_contents_have_no_diff() {
+        assert_eq!(
+            render(Path::new("/tmp/a"), Some(b"same\n"), b"same\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn new_files_diff_against_dev_null() {
+        let diff = plain(render(Path::new("/tmp/new"), None, b"one\n"));
+        assert!(diff.starts_with("--- /dev/null\n+++ b/tmp/new\n@@ -0,0 +1,1 @@\n+one\n"));
+    }
+
+    #[test]
+    fn binary_files_are_summarized() {
+        let diff = plain(render(
+            Path::new("/tmp/icon.png"),
+            Some(b"\x89PNG\0\x01"),
+            b"\x89PNG\0\x02\x03",
+        ));
+        assert_eq!(
+            diff,
+            format!(
+                "Binary file /tmp/icon.png: 6 bytes, sha256 {} -> 7 bytes, sha256 {}\n",
+                &sha256_hex(b"\x89PNG\0\x01")[..12],
+                &sha256_hex(b"\x89PNG\0\x02\x03")[..12]
+            )
+        );
+    }
+}
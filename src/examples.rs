@@ -10,6 +10,10 @@ const EXAMPLE_CONFIG: &str = r#"# Example configuration for setup-my-mac.
 home_directory = "/Users/your-user"
 primary_user = "your-user"
 timezone = "America/Los_Angeles"
+# Sets ComputerName, LocalHostName and HostName; override any one with
+# computer_name, local_host_name or host_name.
+hostname = "your-mac"
+computer_name = "Your MacBook Pro"
 touch_id_sudo = true
 
 [system.trackpad]
@@ -17,6 +21,52 @@ touch_id_sudo = true
 clicking = true
 three_finger_drag = true
 
+# Any other preference, as with `defaults write <domain> <key> -<type> <value>`.
+# type is one of bool, int, float, string, date, array or dict.
+[[system.defaults]]
+domain = "NSGlobalDomain"
+key = "NSDocumentSaveNewDocumentsToCloud"
+type = "bool"
+value = false
+
+[system.finder]
+# Finder restarts once after any of these change.
+show_extensions = true
+show_hidden_files = true
+show_path_bar = true
+show_status_bar = true
+default_view = "list"               # icon, list, column or gallery
+new_window_target = "home"          # home, desktop, documents, computer or a path
+disable_network_ds_store = true
+
+[system.screenshots]
+location = "~/Pictures/Screenshots"
+format = "png"                      # png, jpg, heic, gif, pdf or tiff
+disable_shadow = true
+
+[system.keyboard]
+# Repeat settings apply from the next login.
+key_repeat = 2                      # 15 ms units; lower is faster
+initial_key_repeat = 15
+press_and_hold = false
+fn_as_standard = false
+# Applied now with hidutil, and at every login by a LaunchAgent.
+remap = { caps_lock = "control" }
+
+[system.dock]
+# The Dock restarts once after any of these change.
+autohide = true
+tile_size = 48
+orientation = "bottom"              # left, bottom or right
+show_recents = false
+minimize_effect = "scale"           # genie, scale or suck
+# When set, these lists replace the Dock's apps and folders, in order.
+apps = [
+  "/Applications/Safari.app",
+  "/System/Applications/Mail.app",
+]
+folders = ["~/Downloads"]
+
 [homebrew]
 # Enable Homebrew automation and list formulas/casks to install.
 enable = true
@@ -75,13 +125,30 @@ auto_setup_remote = true
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use super::*;
-    use crate::config::Config;
+    use crate::{
+        config::{self, Config},
+        validate,
+    };
 
     #[test]
     fn example_config_parses_successfully() {
         let cfg: Config = toml::from_str(example_config()).expect("example config parses");
         assert!(cfg.homebrew.enable);
         assert_eq!(cfg.user.dotfiles.len(), 2);
+
+        // The dotfile sources are the user's to create.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, example_config()).unwrap();
+        let raw = config::load_raw(&path, None).unwrap();
+        let problems: Vec<String> = validate::check(&raw)
+            .iter()
+            .map(ToString::to_string)
+            .filter(|problem| !problem.contains("dotfile source"))
+            .collect();
+        assert_eq!(problems, Vec::<String>::new());
     }
 }
@@ -0,0 +1,189 @@
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{command::CommandRunner, config::RemapKey};
+
+const HIDUTIL: &str = "/usr/bin/hidutil";
+
+/// Label of the LaunchAgent that reapplies the remapping at login, since
+/// `hidutil` forgets it on reboot.
+pub const LAUNCH_AGENT_LABEL: &str = "com.github.jjyr.setup-my-mac.keyremap";
+
+/// One `UserKeyMapping` entry: presses of `src` arrive as `dst`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Mapping {
+    pub src: u64,
+    pub dst: u64,
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", KeyName(self.src), KeyName(self.dst))
+    }
+}
+
+/// A usage as its config name, or in hex when it is not one we know.
+struct KeyName(u64);
+
+impl fmt::Display for KeyName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match RemapKey::ALL.iter().find(|key| key.usage() == self.0) {
+            Some(key) => f.write_str(key.as_str()),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// Displays a list of mappings, or `none`.
+pub struct Mappings<'a>(pub &'a [Mapping]);
+
+impl fmt::Display for Mappings<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("none");
+        }
+        for (index, mapping) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{mapping}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The mappings for a config `remap` table, sorted so they compare equal to
+/// what [`read`] returns.
+pub fn mappings<'a>(remap: impl IntoIterator<Item = (&'a RemapKey, &'a RemapKey)>) -> Vec<Mapping> {
+    let mut mappings: Vec<Mapping> = remap
+        .into_iter()
+        .map(|(src, dst)| Mapping {
+            src: src.usage(),
+            dst: dst.usage(),
+        })
+        .collect();
+    mappings.sort();
+    mappings
+}
+
+/// The remapping currently in effect, sorted.
+pub fn read(cmd: &dyn CommandRunner) -> Result<Vec<Mapping>> {
+    let output = cmd
+        .output(HIDUTIL, &["property", "--get", "UserKeyMapping"])
+        .context("reading key remapping")?;
+    if !output.success() {
+        bail!("hidutil property --get failed: {}", output.stderr.trim());
+    }
+    let mut mappings = parse(&output.stdout)?;
+    mappings.sort();
+    Ok(mappings)
+}
+
+/// Replaces the remapping in effect until the next reboot.
+pub fn set(cmd: &dyn CommandRunner, mappings: &[Mapping]) -> Result<()> {
+    let property = property(mappings);
+    let output = cmd
+        .output(HIDUTIL, &["property", "--set", &property])
+        .context("setting key remapping")?;
+    if output.success() {
+        Ok(())
+    } else {
+        bail!("hidutil property --set failed: {}", output.stderr.trim())
+    }
+}
+
+/// The `hidutil property --set` argument for `mappings`.
+fn property(mappings: &[Mapping]) -> String {
+    let entries: Vec<String> = mappings
+        .iter()
+        .map(|mapping| {
+            format!(
+                "{{\"HIDKeyboardModifierMappingSrc\":{:#x},\"HIDKeyboardModifierMappingDst\":{:#x}}}",
+                mapping.src, mapping.dst
+            )
+        })
+        .collect();
+    format!("{{\"UserKeyMapping\":[{}]}}", entries.join(","))
+}
+
+/// Parses `hidutil property --get UserKeyMapping`, which prints an old-style
+/// plist array of dicts, or `(null)` when nothing is remapped.
+fn parse(output: &str) -> Result<Vec<Mapping>> {
+    let mut mappings = Vec::new();
+    for entry in output.split('{').skip(1) {
+        let field = |name: &str| -> Result<u64> {
+            let value = entry
+                .split(';')
+                .find_map(|line| line.trim().strip_prefix(name)?.trim().strip_prefix('='))
+                .with_context(|| format!("missing {name} in hidutil output"))?;
+            value
+                .trim()
+                .parse()
+                .with_context(|| format!("parsing {name} in hidutil output"))
+        };
+        mappings.push(Mapping {
+            src: field("HIDKeyboardModifierMappingSrc")?,
+            dst: field("HIDKeyboardModifierMappingDst")?,
+        });
+    }
+    Ok(mappings)
+}
+
+/// A LaunchAgent that applies `mappings` at every login.
+pub fn launch_agent(mappings: &[Mapping]) -> Result<String> {
+    let mut agent = plist::Dictionary::new();
+    agent.insert("Label".into(), LAUNCH_AGENT_LABEL.into());
+    agent.insert(
+        "ProgramArguments".into(),
+        plist::Value::Array(vec![
+            HIDUTIL.into(),
+            "property".into(),
+            "--set".into(),
+            property(mappings).into(),
+        ]),
+    );
+    agent.insert("RunAtLoad".into(), true.into());
+
+    let mut out = Vec::new();
+    plist::Value::Dictionary(agent)
+        .to_writer_xml(&mut out)
+        .context("rendering LaunchAgent")?;
+    out.push(b'\n');
+    String::from_utf8(out).context("LaunchAgent is not valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_output_is_parsed() {
+        let output = "(\n        {\n        HIDKeyboardModifierMappingDst = 30064771296;\n        HIDKeyboardModifierMappingSrc = 30064771129;\n    }\n)\n";
+        let mappings = parse(output).unwrap();
+        assert_eq!(
+            mappings,
+            [Mapping {
+                src: RemapKey::CapsLock.usage(),
+                dst: RemapKey::Control.usage(),
+            }]
+        );
+        assert_eq!(Mappings(&mappings).to_string(), "caps_lock -> control");
+        assert_eq!(parse("(null)\n").unwrap(), []);
+    }
+
+    #[test]
+    fn launch_agent_sets_the_same_property() {
+        let mappings = mappings([(&RemapKey::CapsLock, &RemapKey::Escape)]);
+        assert_eq!(
+            property(&mappings),
+            r#"{"UserKeyMapping":[{"HIDKeyboardModifierMappingSrc":0x700000039,"HIDKeyboardModifierMappingDst":0x700000029}]}"#
+        );
+        let agent = launch_agent(&mappings).unwrap();
+        assert!(agent.contains(&format!("<string>{LAUNCH_AGENT_LABEL}</string>")));
+        let argument = property(&mappings).replace('"', "&quot;");
+        assert!(agent.contains(&format!("<string>{argument}</string>")));
+        assert!(agent.contains("<key>RunAtLoad</key>\n\t<true/>"));
+    }
+}
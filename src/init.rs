@@ -0,0 +1,316 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use console::style;
+use dialoguer::{Confirm, Input, MultiSelect};
+use tempfile::NamedTempFile;
+
+use crate::{
+    capture,
+    command::SystemRunner,
+    config,
+    steps::git,
+    util::{self, is_interactive},
+    validate,
+};
+
+/// Formulae offered by `init`; anything else can be added to the file later.
+const BREWS: [&str; 10] = [
+    "git", "gh", "ripgrep", "fd", "fzf", "jq", "neovim", "starship", "zoxide", "mise",
+];
+
+/// Casks offered by `init`.
+const CASKS: [&str; 10] = [
+    "iterm2",
+    "ghostty",
+    "visual-studio-code",
+    "rectangle",
+    "raycast",
+    "1password",
+    "firefox",
+    "google-chrome",
+    "slack",
+    "docker",
+];
+
+/// What the wizard asked for.
+#[derive(Debug, Default)]
+pub struct Answers {
+    pub home_directory: PathBuf,
+    pub primary_user: String,
+    pub timezone: Option<String>,
+    pub touch_id_sudo: bool,
+    pub brews: Vec<String>,
+    pub casks: Vec<String>,
+    pub user_name: Option<String>,
+    pub user_email: Option<String>,
+}
+
+/// Asks a few questions and writes a minimal config to `path`.
+pub fn run(path: &Path) -> Result<()> {
+    if !is_interactive() {
+        bail!(
+            "init asks questions and needs a terminal; \
+             use `setup-my-mac --example-config > {}` instead",
+            path.display()
+        );
+    }
+
+    if path.exists() {
+        let overwrite = Confirm::new()
+            .with_prompt(format!("{} already exists. Overwrite it?", path.display()))
+            .default(false)
+            .interact()?;
+        if !overwrite {
+            println!(
+                "{} {}",
+                style("↷").yellow(),
+                style(format!("Left {} unchanged", path.display())).yellow()
+            );
+            return Ok(());
+        }
+    }
+
+    let answers = ask()?;
+    write_config(path, &render(&answers))?;
+    println!(
+        "{} Wrote {}; preview it with `setup-my-mac --dry-run`",
+        style("✔").green().bold(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn ask() -> Result<Answers> {
+    let cmd = SystemRunner;
+    let git_default = |key| git::git_get(&cmd, None, key).ok().flatten();
+    let localtime = capture::timezone(Path::new("/etc/localtime"));
+
+    let primary_user: String = Input::new()
+        .with_prompt("Primary user")
+        .with_initial_text(util::current_user().unwrap_or_default())
+        .validate_with(|user: &String| {
+            if user.trim().is_empty() {
+                Err("the primary user cannot be empty")
+            } else {
+                Ok(())
+            }
+        })
+        .interact_text()?;
+    let home: String = Input::new()
+        .with_prompt("Home directory")
+        .with_initial_text(format!("/Users/{}", primary_user.trim()))
+        .validate_with(|home: &String| {
+            if Path::new(home.trim()).is_absolute() {
+                Ok(())
+            } else {
+                Err("the home directory must be an absolute path")
+            }
+        })
+        .interact_text()?;
+    let timezone: String = Input::new()
+        .with_prompt("Timezone (blank to leave it alone)")
+        .with_initial_text(localtime.unwrap_or_default())
+        .allow_empty(true)
+        .validate_with(|tz: &String| {
+            let tz = tz.trim();
+            if tz.is_empty() || validate::is_timezone(tz) {
+                Ok(())
+            } else {
+                Err(format!("`{tz}` is not an IANA timezone name"))
+            }
+        })
+        .interact_text()?;
+    let touch_id_sudo = Confirm::new()
+        .with_prompt("Use Touch ID for sudo?")
+        .default(true)
+        .interact()?;
+
+    let brews = pick(
+        "Formulae to install (space toggles, enter confirms)",
+        &BREWS,
+    )?;
+    let casks = pick("Casks to install (space toggles, enter confirms)", &CASKS)?;
+
+    let user_name: String = Input::new()
+        .with_prompt("Git user name (blank to skip)")
+        .with_initial_text(git_default("user.name").unwrap_or_default())
+        .allow_empty(true)
+        .interact_text()?;
+    let user_email: String = Input::new()
+        .with_prompt("Git email (blank to skip)")
+        .with_initial_text(git_default("user.email").unwrap_or_default())
+        .allow_empty(true)
+        .validate_with(|email: &String| {
+            let email = email.trim();
+            if email.is_empty() || email.contains('@') {
+                Ok(())
+            } else {
+                Err("that does not look like an email address")
+            }
+        })
+        .interact_text()?;
+
+    let optional = |value: String| Some(value.trim().to_owned()).filter(|value| !value.is_empty());
+    Ok(Answers {
+        home_directory: PathBuf::from(home.trim()),
+        primary_user: primary_user.trim().to_owned(),
+        timezone: optional(timezone),
+        touch_id_sudo,
+        brews,
+        casks,
+        user_name: optional(user_name),
+        user_email: optional(user_email),
+    })
+}
+
+fn pick(prompt: &str, items: &[&str]) -> Result<Vec<String>> {
+    let picked = MultiSelect::new()
+        .with_prompt(prompt)
+        .items(items)
+        .interact()?;
+    Ok(picked
+        .into_iter()
+        .map(|index| items[index].to_owned())
+        .collect())
+}
+
+/// A commented config holding only what was answered.
+pub fn render(answers: &Answers) -> String {
+    let mut out = String::from(
+        "# Written by `setup-my-mac init`.\n\
+         # `setup-my-mac --example-config` shows every available option.\n\n",
+    );
+
+    out.push_str(
+        "[system]\n# Whose machine this is; `~` in config paths expands to home_directory.\n",
+    );
+    let _ = writeln!(
+        out,
+        "home_directory = {}",
+        quote(&answers.home_directory.display().to_string())
+    );
+    let _ = writeln!(out, "primary_user = {}", quote(&answers.primary_user));
+    if let Some(tz) = &answers.timezone {
+        let _ = writeln!(out, "timezone = {}", quote(tz));
+    }
+    let _ = writeln!(out, "touch_id_sudo = {}", answers.touch_id_sudo);
+
+    if !answers.brews.is_empty() || !answers.casks.is_empty() {
+        out.push_str("\n[homebrew]\n# Installed with `brew bundle`; add more names as needed.\n");
+        out.push_str("enable = true\n");
+        let _ = writeln!(out, "brews = {}", list(&answers.brews));
+        let _ = writeln!(out, "casks = {}", list(&answers.casks));
+    }
+
+    out.push_str("\n[user]\n");
+    if answers.user_name.is_some() || answers.user_email.is_some() {
+        out.push_str("\n[user.git]\n# Set with `git config --global`.\nenable = true\n");
+        if let Some(name) = &answers.user_name {
+            let _ = writeln!(out, "user_name = {}", quote(name));
+        }
+        if let Some(email) = &answers.user_email {
+            let _ = writeln!(out, "user_email = {}", quote(email));
+        }
+    }
+    out
+}
+
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_owned()).to_string()
+}
+
+fn list(values: &[String]) -> String {
+    if values.is_empty() {
+        return "[]".to_owned();
+    }
+    let mut out = String::from("[\n");
+    for value in values {
+        let _ = writeln!(out, "  {},", quote(value));
+    }
+    out.push(']');
+    out
+}
+
+/// Checks `contents` the way `validate` does, then moves it into place so a
+/// bad answer never replaces a working config.
+fn write_config(path: &Path, contents: &str) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let mut tmp = NamedTempFile::new_in(dir).context("allocating temp file")?;
+    tmp.write_all(contents.as_bytes())?;
+    tmp.flush()?;
+
+    let raw = config::load_raw(tmp.path(), None)?;
+    let diagnostics = validate::check(&raw);
+    if !diagnostics.is_empty() {
+        for diagnostic in &diagnostics {
+            eprintln!("{diagnostic}");
+        }
+        bail!("the generated config has {} problem(s)", diagnostics.len());
+    }
+
+    tmp.persist(path)
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answers() -> Answers {
+        Answers {
+            home_directory: PathBuf::from("/Users/jane"),
+            primary_user: "jane".to_owned(),
+            // A real zone would make the test depend on the host's tzdata.
+            timezone: None,
+            touch_id_sudo: true,
+            brews: vec!["git".to_owned(), "ripgrep".to_owned()],
+            casks: Vec::new(),
+            user_name: Some("Jane \"JD\" Doe".to_owned()),
+            user_email: None,
+        }
+    }
+
+    #[test]
+    fn rendered_config_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        write_config(&path, &render(&answers())).unwrap();
+
+        let cfg = config::load_config(&path, None).unwrap().config;
+        assert_eq!(cfg.system.primary_user, "jane");
+        assert_eq!(cfg.system.timezone, None);
+        assert!(cfg.system.touch_id_sudo);
+        assert!(cfg.homebrew.enable);
+        assert_eq!(cfg.homebrew.brews, ["git", "ripgrep"]);
+        let git = cfg.user.git.unwrap();
+        assert_eq!(git.user_name.as_deref(), Some("Jane \"JD\" Doe"));
+        assert_eq!(git.user_email, None);
+    }
+
+    #[test]
+    fn invalid_answers_leave_the_existing_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "# mine\n").unwrap();
+
+        let answers = Answers {
+            timezone: Some("Mars/Olympus_Mons".to_owned()),
+            ..answers()
+        };
+        let err = write_config(&path, &render(&answers)).unwrap_err();
+
+        assert_eq!(err.to_string(), "the generated config has 1 problem(s)");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "# mine\n");
+    }
+}
@@ -0,0 +1,720 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use console::style;
+use dialoguer::Confirm;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::{self, Account},
+    command::{AsUser, CommandRunner},
+    defaults::{self, DefaultsValue, Key},
+    hidutil::{self, Mapping, Mappings},
+    state,
+    steps::{self, git, system, Change},
+    sudo::SudoHelper,
+    util,
+};
+
+/// How to put back what a single applied [`Change`] replaced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Revert {
+    Timezone {
+        previous: String,
+    },
+    Hostname {
+        name: String,
+        previous: String,
+    },
+    /// A root-owned file; `previous: None` means it did not exist.
+    PrivilegedFile {
+        path: PathBuf,
+        previous: Option<String>,
+    },
+    Defaults {
+        domain: String,
+        key: String,
+        #[serde(default)]
+        current_host: bool,
+        previous: Option<DefaultsValue>,
+    },
+    KeyRemap {
+        previous: Vec<Mapping>,
+    },
+    GitConfig {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file: Option<PathBuf>,
+        key: String,
+        previous: Option<String>,
+    },
+    /// A user file; `previous: None` means it did not exist.
+    File {
+        path: PathBuf,
+        previous: Option<String>,
+        /// Mode the file was written with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
+    },
+    Mode {
+        path: PathBuf,
+        previous: u32,
+    },
+    /// Move a backup made before syncing back into place.
+    Restore {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    /// Delete a file that was created from scratch.
+    Remove {
+        path: PathBuf,
+    },
+}
+
+impl Revert {
+    /// The revert for `change`, or `None` when it cannot be undone (e.g.
+    /// Homebrew installs, a timezone that could not be read, or a hostname
+    /// that was not set).
+    pub fn for_change(change: &Change) -> Option<Revert> {
+        match change {
+            Change::Timezone { current, .. } => current
+                .clone()
+                .map(|previous| Revert::Timezone { previous }),
+            Change::Hostname { name, current, .. } => {
+                current.clone().map(|previous| Revert::Hostname {
+                    name: name.clone(),
+                    previous,
+                })
+            }
+            Change::TouchIdSudo { path, current, .. } => Some(Revert::PrivilegedFile {
+                path: path.clone(),
+                previous: current.clone(),
+            }),
+            Change::Defaults {
+                domain,
+                key,
+                current_host,
+                current,
+                ..
+            } => Some(Revert::Defaults {
+                domain: domain.clone(),
+                key: key.clone(),
+                current_host: *current_host,
+                previous: current.clone(),
+            }),
+            Change::KeyRemap { current, .. } => Some(Revert::KeyRemap {
+                previous: current.clone(),
+            }),
+            Change::BrewBundle { .. } | Change::RestartApp { .. } => None,
+            Change::Backup { path, backup } => Some(Revert::Restore {
+                path: path.clone(),
+                backup: backup.clone(),
+            }),
+            Change::CopyFile { target, .. } => Some(Revert::Remove {
+                path: target.clone(),
+            }),
+            Change::WriteFile {
+                path,
+                current,
+                mode,
+                ..
+            } => Some(Revert::File {
+                path: path.clone(),
+                previous: current.clone(),
+                mode: *mode,
+            }),
+            Change::SetMode { path, current, .. } => Some(Revert::Mode {
+                path: path.clone(),
+                previous: *current,
+            }),
+            Change::GitConfig {
+                file, key, current, ..
+            } => Some(Revert::GitConfig {
+                file: file.clone(),
+                key: key.clone(),
+                previous: current.clone(),
+            }),
+        }
+    }
+
+    /// Puts back what the change replaced. Restored files are handed to
+    /// `owner` when undo runs as root on their behalf.
+    fn apply(
+        &self,
+        cmd: &dyn CommandRunner,
+        sudo: &mut SudoHelper,
+        owner: Option<&Owner>,
+    ) -> Result<()> {
+        match self {
+            Revert::Timezone { previous } => system::set_timezone(sudo, previous),
+            Revert::Hostname { name, previous } => system::set_hostname(sudo, name, previous),
+            Revert::PrivilegedFile { path, previous } => match previous {
+                Some(previous) => system::write_privileged_file(sudo, path, previous),
+                None => {
+                    let path = path.to_str().context("path not utf8")?;
+                    sudo.run("/bin/rm", &["-f", path])
+                }
+            },
+            Revert::Defaults {
+                domain,
+                key,
+                current_host,
+                previous,
+            } => {
+                let key = Key {
+                    domain,
+                    key,
+                    current_host: *current_host,
+                };
+                match previous {
+                    Some(previous) => defaults::write(cmd, key, previous),
+                    None => defaults::delete(cmd, key),
+                }
+            }
+            Revert::KeyRemap { previous } => hidutil::set(cmd, previous),
+            Revert::GitConfig {
+                file,
+                key,
+                previous,
+            } => match previous {
+                Some(previous) => git::set_git_config(cmd, file.as_deref(), key, previous),
+                None => git::unset_git_config(cmd, file.as_deref(), key),
+            },
+            Revert::File {
+                path,
+                previous,
+                mode,
+            } => match previous {
+                Some(previous) => {
+                    steps::write_file(path, previous, *mode)?;
+                    match owner {
+                        Some(owner) => steps::hand_over(path, &owner.account, &owner.home),
+                        None => Ok(()),
+                    }
+                }
+                None => remove_file(path),
+            },
+            Revert::Mode { path, previous } => steps::set_mode(path, *previous),
+            Revert::Restore { path, backup } => {
+                if path.is_dir() {
+                    fs::remove_dir_all(path)
+                        .with_context(|| format!("removing {}", path.display()))?;
+                } else {
+                    remove_file(path)?;
+                }
+                fs::rename(backup, path)
+                    .with_context(|| format!("renaming {} -> {}", backup.display(), path.display()))
+            }
+            Revert::Remove { path } => remove_file(path),
+        }
+    }
+}
+
+impl fmt::Display for Revert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Revert::Timezone { previous } => write!(f, "set timezone back to {previous}"),
+            Revert::Hostname { name, previous } => write!(f, "set {name} back to {previous}"),
+            Revert::PrivilegedFile { path, previous } | Revert::File { path, previous, .. } => {
+                match previous {
+                    Some(_) => write!(f, "restore previous contents of {}", path.display()),
+                    None => write!(f, "remove {}", path.display()),
+                }
+            }
+            Revert::Defaults {
+                domain,
+                key,
+                current_host,
+                previous,
+            } => {
+                let key = Key {
+                    domain,
+                    key,
+                    current_host: *current_host,
+                };
+                match previous {
+                    Some(previous) => {
+                        write!(
+                            f,
+                            "defaults write {key} -{} {previous}",
+                            previous.type_name()
+                        )
+                    }
+                    None => write!(f, "defaults delete {key}"),
+                }
+            }
+            Revert::KeyRemap { previous } => {
+                write!(f, "remap keys back to {}", Mappings(previous))
+            }
+            Revert::GitConfig {
+                file,
+                key,
+                previous,
+            } => {
+                match file {
+                    Some(file) => write!(f, "git config --file {} ", file.display())?,
+                    None => write!(f, "git config --global ")?,
+                }
+                match previous {
+                    Some(previous) => write!(f, "{key} {previous}"),
+                    None => write!(f, "--unset {key}"),
+                }
+            }
+            Revert::Mode { path, previous } => write!(f, "chmod {previous:o} {}", path.display()),
+            Revert::Restore { path, backup } => {
+                write!(f, "move {} back to {}", backup.display(), path.display())
+            }
+            Revert::Remove { path } => write!(f, "remove {}", path.display()),
+        }
+    }
+}
+
+/// The user a root run acted for, and their home directory.
+struct Owner {
+    account: Account,
+    home: PathBuf,
+}
+
+impl Owner {
+    fn lookup(cmd: &dyn CommandRunner, user: &str) -> Result<Self> {
+        let home =
+            util::home_of(user).with_context(|| format!("finding the home directory of {user}"))?;
+        Ok(Owner {
+            account: account::lookup(cmd, user)?,
+            home: PathBuf::from(home),
+        })
+    }
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("removing {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub step: String,
+    /// User the change was made as, when a root run acted on their behalf.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub revert: Revert,
+}
+
+/// Append-only record of the changes applied by one run, stored as JSON
+/// lines under `<state dir>/journal/<run id>.jsonl`.
+pub struct Journal {
+    /// `None` for a staged run, which records nothing.
+    path: Option<PathBuf>,
+    /// Who entries are made on behalf of, and who owns the journal file.
+    account: Option<Account>,
+    home: PathBuf,
+}
+
+impl Journal {
+    /// A journal for a new run under the state dir of `home`. Nothing is
+    /// written until the first entry.
+    pub fn create(home: &Path) -> Self {
+        Self::create_in(&journal_dir(home), home, SystemTime::now())
+    }
+
+    fn create_in(dir: &Path, home: &Path, now: SystemTime) -> Self {
+        let base: String = humantime::format_rfc3339_seconds(now)
+            .to_string()
+            .chars()
+            .filter(|c| !matches!(c, '-' | ':'))
+            .collect();
+        let mut path = dir.join(format!("{base}.jsonl"));
+        let mut attempt = 1;
+        while path.exists() {
+            path = dir.join(format!("{base}-{attempt}.jsonl"));
+            attempt += 1;
+        }
+        Journal {
+            path: Some(path),
+            account: None,
+            home: home.to_owned(),
+        }
+    }
+
+    /// A journal for a `--root-dir` run. Its changes land in the staged
+    /// tree, not on this machine, so there is nothing for `undo` to revert.
+    pub fn staged() -> Self {
+        Journal {
+            path: None,
+            account: None,
+            home: PathBuf::new(),
+        }
+    }
+
+    /// Marks later entries as made on behalf of `account`, and hands the
+    /// journal to it so its own runs can read and undo them.
+    pub fn set_account(&mut self, account: Option<&Account>) {
+        self.account = account.cloned();
+    }
+
+    /// The run id, once at least one entry has been recorded.
+    pub fn run_id(&self) -> Option<String> {
+        let path = self.path.as_ref()?;
+        path.exists()
+            .then(|| path.file_stem())
+            .flatten()
+            .map(|stem| stem.to_string_lossy().into_owned())
+    }
+
+    pub fn record(&mut self, step: &str, revert: Revert) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let entry = JournalEntry {
+            step: step.to_owned(),
+            user: self.account.as_ref().map(|account| account.user.clone()),
+            revert,
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        match &self.account {
+            Some(account) => steps::hand_over(path, account, &self.home),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn journal_dir(home: &Path) -> PathBuf {
+    state::state_dir(home).join("journal")
+}
+
+/// Run ids with a journal, oldest first.
+pub fn list_runs(dir: &Path) -> Result<Vec<String>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut runs = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "jsonl") {
+            if let Some(stem) = path.file_stem() {
+                runs.push(stem.to_string_lossy().into_owned());
+            }
+        }
+    }
+    runs.sort();
+    Ok(runs)
+}
+
+fn read_entries(path: &Path) -> Result<Vec<JournalEntry>> {
+    let data =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    data.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid journal entry in {}", path.display()))
+        })
+        .collect()
+}
+
+fn write_entries(path: &Path, entries: &[JournalEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return fs::remove_file(path).with_context(|| format!("removing {}", path.display()));
+    }
+    let mut data = String::new();
+    for entry in entries {
+        data.push_str(&serde_json::to_string(entry)?);
+        data.push('\n');
+    }
+    fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Reverts the changes of `run` (the latest run when `None`) in reverse
+/// order. Each successful revert is dropped from the journal, so an
+/// interrupted undo can simply be retried.
+pub fn undo(
+    home: &Path,
+    run: Option<&str>,
+    cmd: &dyn CommandRunner,
+    sudo: &mut SudoHelper,
+    assume_yes: bool,
+    interactive: bool,
+) -> Result<()> {
+    let dir = journal_dir(home);
+    let runs = list_runs(&dir)?;
+    let run_id = match run {
+        Some(run) if runs.iter().any(|r| r == run) => run.to_owned(),
+        Some(run) => {
+            if runs.is_empty() {
+                bail!("no run `{run}` found; nothing has been recorded yet");
+            }
+            bail!("no run `{run}` found; known runs: {}", runs.join(", "));
+        }
+        None => match runs.last() {
+            Some(latest) => latest.clone(),
+            None => {
+                println!("{} Nothing to undo", style("↷").yellow());
+                return Ok(());
+            }
+        },
+    };
+
+    let path = dir.join(format!("{run_id}.jsonl"));
+    let mut entries = read_entries(&path)?;
+
+    println!("Undoing run {}:", style(&run_id).bold());
+    for entry in entries.iter().rev() {
+        println!("  {} [{}] {}", style("↺").cyan(), entry.step, entry.revert);
+    }
+
+    if !assume_yes {
+        if !interactive {
+            bail!("confirmation required but no terminal is attached; pass --yes to undo non-interactively");
+        }
+        let confirmed = Confirm::new()
+            .with_prompt(format!("Revert {} change(s)?", entries.len()))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            println!(
+                "{} {}",
+                style("↷").yellow(),
+                style("Undo cancelled").yellow()
+            );
+            return Ok(());
+        }
+    }
+
+    // Apps reading reverted preferences restart once, after every revert.
+    let mut restarts: Vec<(Option<String>, &str)> = Vec::new();
+    let root = account::is_root(cmd)?;
+    while let Some(entry) = entries.pop() {
+        let as_user = AsUser::new(cmd, entry.user.as_deref());
+        // Files rewritten as root go back to the user the run acted for.
+        let owner = match (&entry.revert, entry.user.as_deref()) {
+            (
+                Revert::File {
+                    previous: Some(_), ..
+                },
+                Some(user),
+            ) if root => Some(Owner::lookup(cmd, user)?),
+            _ => None,
+        };
+        if let Err(err) = entry.revert.apply(&as_user, sudo, owner.as_ref()) {
+            entries.push(entry.clone());
+            write_entries(&path, &entries)?;
+            return Err(err).with_context(|| format!("reverting {}", entry.revert));
+        }
+        write_entries(&path, &entries)?;
+
+        if let Revert::Defaults { domain, key, .. } = &entry.revert {
+            if let Some(app) = defaults::owning_app(domain, key) {
+                let restart = (entry.user.clone(), app);
+                if !restarts.contains(&restart) {
+                    restarts.push(restart);
+                }
+            }
+        }
+    }
+    for (user, app) in restarts {
+        defaults::restart(&AsUser::new(cmd, user.as_deref()), app)?;
+    }
+
+    println!("{} Run {run_id} undone", style("✔").green().bold());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::command::ScriptedRunner;
+
+    #[test]
+    fn journal_entries_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut journal = Journal::create_in(dir.path(), dir.path(), now);
+        journal
+            .record(
+                "git",
+                Revert::GitConfig {
+                    file: None,
+                    key: "user.email".into(),
+                    previous: None,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(list_runs(dir.path()).unwrap(), ["20231114T221320Z"]);
+        let entries = read_entries(journal.path.as_ref().unwrap()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].step, "git");
+
+        let second = Journal::create_in(dir.path(), dir.path(), now);
+        assert!(second.path.unwrap().ends_with("20231114T221320Z-1.jsonl"));
+    }
+
+    #[test]
+    fn entries_made_for_an_account_are_handed_to_it() {
+        // Handing the journal to ourselves needs no privileges.
+        let home = tempfile::tempdir().unwrap();
+        let me = fs::metadata(home.path()).unwrap();
+        let account = Account {
+            user: "test".to_owned(),
+            uid: me.uid(),
+            gid: me.gid(),
+        };
+        let dir = home.path().join(".local/state/setup-my-mac/journal");
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut journal = Journal::create_in(&dir, home.path(), now);
+        journal.set_account(Some(&account));
+        journal
+            .record(
+                "dock",
+                Revert::Defaults {
+                    domain: "com.apple.dock".into(),
+                    key: "autohide".into(),
+                    current_host: false,
+                    previous: None,
+                },
+            )
+            .unwrap();
+
+        let path = journal.path.as_ref().unwrap();
+        assert_eq!(fs::metadata(path).unwrap().uid(), me.uid());
+        let entries = read_entries(path).unwrap();
+        assert_eq!(entries[0].user.as_deref(), Some("test"));
+    }
+
+    #[test]
+    fn staged_runs_are_not_journaled() {
+        let mut journal = Journal::staged();
+        journal
+            .record(
+                "system",
+                Revert::Hostname {
+                    name: "ComputerName".into(),
+                    previous: "Old Mac".into(),
+                },
+            )
+            .unwrap();
+        assert_eq!(journal.run_id(), None);
+    }
+
+    #[test]
+    fn reverts_restore_files_and_git_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let written = dir.path().join("config");
+        let created = dir.path().join("created");
+        fs::write(&written, "new\n").unwrap();
+        fs::write(&created, "new\n").unwrap();
+
+        let runner = Rc::new(ScriptedRunner::new());
+        runner.on("git config --global user.name Old", 0, "").on(
+            "git config --global --unset user.email",
+            5,
+            "",
+        );
+        let mut sudo = SudoHelper::new(runner.clone(), false);
+
+        let reverts = [
+            Revert::File {
+                path: written.clone(),
+                previous: Some("old\n".into()),
+                mode: None,
+            },
+            Revert::Remove {
+                path: created.clone(),
+            },
+            Revert::GitConfig {
+                file: None,
+                key: "user.name".into(),
+                previous: Some("Old".into()),
+            },
+            Revert::GitConfig {
+                file: None,
+                key: "user.email".into(),
+                previous: None,
+            },
+        ];
+        for revert in &reverts {
+            revert.apply(runner.as_ref(), &mut sudo, None).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&written).unwrap(), "old\n");
+        assert!(!created.exists());
+        assert_eq!(
+            runner.calls(),
+            [
+                "git config --global user.name Old",
+                "git config --global --unset user.email"
+            ]
+        );
+    }
+
+    #[test]
+    fn restored_files_get_their_mode_and_owner_back() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".ssh/config");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "new\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let change = Change::WriteFile {
+            path: path.clone(),
+            current: Some("old\n".into()),
+            contents: "new\n".into(),
+            mode: Some(0o600),
+        };
+        let revert = Revert::for_change(&change).unwrap();
+        assert_eq!(
+            revert,
+            Revert::File {
+                path: path.clone(),
+                previous: Some("old\n".into()),
+                mode: Some(0o600),
+            }
+        );
+
+        // Handing the file to ourselves needs no privileges.
+        let meta = fs::metadata(dir.path()).unwrap();
+        let owner = Owner {
+            account: Account {
+                user: "test".into(),
+                uid: meta.uid(),
+                gid: meta.gid(),
+            },
+            home: dir.path().to_owned(),
+        };
+        let runner = Rc::new(ScriptedRunner::new());
+        let mut sudo = SudoHelper::new(runner.clone(), false);
+        revert
+            .apply(runner.as_ref(), &mut sudo, Some(&owner))
+            .unwrap();
+
+        let restored = fs::metadata(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old\n");
+        assert_eq!(restored.permissions().mode() & 0o777, 0o600);
+        assert_eq!(restored.uid(), meta.uid());
+        assert_eq!(runner.calls(), Vec::<String>::new());
+    }
+}
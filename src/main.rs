@@ -1,31 +1,108 @@
+mod account;
+mod capture;
+mod command;
 mod config;
+mod defaults;
+mod diff;
 mod examples;
+mod hidutil;
+mod init;
+mod journal;
+mod report;
 mod runner;
+mod schema;
+mod state;
 mod steps;
 mod sudo;
+mod template;
 mod util;
+mod validate;
 
 use std::path::PathBuf;
+use std::rc::Rc;
 
-use anyhow::Result;
-use clap::Parser;
-use steps::StepKind;
+use anyhow::{bail, Context, Result};
+use clap::{
+    builder::PossibleValuesParser, builder::TypedValueParser, Parser, Subcommand, ValueEnum,
+};
+use steps::Step;
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Opinionated Mac bootstrapper", long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Path to the configuration file
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, global = true, default_value = "config.toml")]
     config: PathBuf,
 
     /// Comma separated list of steps to execute (defaults to everything)
-    #[arg(long, value_delimiter = ',', value_enum)]
-    steps: Option<Vec<StepKind>>,
+    #[arg(long, global = true, value_delimiter = ',', value_parser = step_parser())]
+    steps: Option<Vec<&'static dyn Step>>,
+
+    /// Apply this `[profiles.<name>]` overlay instead of the ones matching this machine
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
 
     /// Print an example configuration to stdout and exit
     #[arg(long)]
     example_config: bool,
+
+    /// Show every change the selected steps would make without applying it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Keep running the remaining steps after a failure and print a summary
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Run without asking for confirmation (required when stdin is not a terminal)
+    #[arg(short, long, global = true)]
+    yes: bool,
+
+    /// Skip steps that completed in the previous run of the same config
+    #[arg(long)]
+    resume: bool,
+
+    /// Write a JSON report of every step and action to this file
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Write files under this directory instead of onto the machine, to inspect or diff the result
+    #[arg(long, global = true, value_name = "PATH")]
+    root_dir: Option<PathBuf>,
+
+    /// Output format; `json` prints the run report to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Compare the machine with the config and exit non-zero on drift
+    Status,
+    /// Check the config for unknown keys and invalid values without running anything
+    Validate,
+    /// Print a JSON Schema for the config file, for editor completion
+    Schema,
+    /// Print a config describing this machine's current settings
+    Capture,
+    /// Answer a few questions to write a starter config
+    Init,
+    /// Revert the changes journaled by a previous run
+    Undo {
+        /// Run id to revert (defaults to the most recent run)
+        #[arg(long)]
+        run: Option<String>,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
 }
 
 fn main() -> Result<()> {
@@ -33,9 +110,18 @@ fn main() -> Result<()> {
     init_tracing();
 
     let Cli {
+        command,
         config: cfg_path,
         steps,
+        profile,
         example_config,
+        dry_run,
+        keep_going,
+        yes,
+        resume,
+        report,
+        root_dir,
+        output,
     } = Cli::parse();
 
     if example_config {
@@ -43,16 +129,103 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    println!("Using configuration file: {}", cfg_path.display());
-    let bundle = config::load_config(&cfg_path)?;
+    if let Some(Commands::Undo { run }) = command {
+        let cmd = Rc::new(command::SystemRunner);
+        let interactive = util::is_interactive();
+        let mut sudo = sudo::SudoHelper::new(cmd.clone(), interactive);
+        // Runs journal under the configured home; without a usable config,
+        // fall back to the home of whoever invoked us.
+        let home = config::load_config(&cfg_path, profile.as_deref())
+            .map(|bundle| bundle.config.system.home_directory)
+            .ok()
+            .or_else(|| {
+                util::invoking_user()
+                    .and_then(|user| util::home_of(&user))
+                    .map(PathBuf::from)
+            })
+            .context("unable to find the home directory holding the undo journal")?;
+        return journal::undo(
+            &home,
+            run.as_deref(),
+            cmd.as_ref(),
+            &mut sudo,
+            yes,
+            interactive,
+        );
+    }
 
-    let mut runner = runner::Runner::new(bundle);
+    if let Some(Commands::Init) = command {
+        return init::run(&cfg_path);
+    }
+
+    if let Some(Commands::Schema) = command {
+        print!("{}", schema::config_schema());
+        return Ok(());
+    }
+
+    let root_dir = root_dir.map(std::path::absolute).transpose()?;
+    if let Some(Commands::Capture) = command {
+        let system = command::SystemRunner;
+        // Under sudo, git and brew run as the user who invoked it.
+        let sudo_user =
+            util::invoking_user().filter(|user| util::current_user().as_ref() != Some(user));
+        let cmd = command::AsUser::new(&system, sudo_user.as_deref());
+        let machine = capture::Machine::current(&cmd, root_dir.as_deref());
+        print!("{}", capture::capture(&machine)?);
+        return Ok(());
+    }
+
+    if let Some(Commands::Validate) = command {
+        return validate::run(&cfg_path, profile.as_deref());
+    }
+
+    let json_output = output == OutputFormat::Json;
+    let bundle = config::load_config(&cfg_path, profile.as_deref())?;
+    let mut banner = format!("Using configuration file: {}", cfg_path.display());
+    for source in bundle.sources.iter().filter(|source| **source != cfg_path) {
+        banner.push_str(&format!("\n  including {}", source.display()));
+    }
+    if !bundle.profiles.is_empty() {
+        banner.push_str(&format!("\n  profile {}", bundle.profiles.join(", ")));
+    }
+    if json_output {
+        eprintln!("{banner}");
+    } else {
+        println!("{banner}");
+    }
+
+    let options = runner::RunOptions {
+        dry_run,
+        keep_going,
+        assume_yes: yes,
+        interactive: util::is_interactive(),
+        resume,
+        report,
+        json_output,
+        root_dir,
+    };
+    let mut runner = runner::Runner::new(bundle, options);
+    if let Some(Commands::Status) = command {
+        let drift = runner.status(steps)?;
+        if drift > 0 {
+            bail!("{drift} item(s) out of sync with {}", cfg_path.display());
+        }
+        return Ok(());
+    }
     runner.run(steps)?;
 
     Ok(())
 }
 
+fn step_parser() -> impl TypedValueParser<Value = &'static dyn Step> {
+    PossibleValuesParser::new(steps::names())
+        .map(|name| steps::find(&name).expect("possible values come from the registry"))
+}
+
 fn init_tracing() {
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    let _ = tracing_subscriber::fmt().with_env_filter(filter).try_init();
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .try_init();
 }
@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{command::CommandRecord, steps::Change};
+
+/// Machine-readable record of a whole run, written by `--report` and
+/// `--output json`.
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub config: PathBuf,
+    pub dry_run: bool,
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_ms: u128,
+    pub success: bool,
+    pub steps: Vec<StepReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepReport {
+    pub name: &'static str,
+    /// One of `planned`, `applied`, `unchanged`, `skipped` or `failed`.
+    pub status: &'static str,
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_ms: u128,
+    /// The error followed by its causes, outermost first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub error: Vec<String>,
+    pub planned: Vec<Change>,
+    pub applied: Vec<Change>,
+    pub commands: Vec<CommandRecord>,
+}
+
+impl RunReport {
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let json = self.to_json()?;
+        fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("serializing run report")
+    }
+}
+
+pub fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+pub fn elapsed_ms(start: SystemTime, end: SystemTime) -> u128 {
+    end.duration_since(start)
+        .unwrap_or(Duration::ZERO)
+        .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::util::sha256_hex;
+
+    fn report() -> RunReport {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let end = start + Duration::from_millis(1500);
+        let write = Change::WriteFile {
+            path: PathBuf::from("/Users/test/.ssh/config"),
+            current: None,
+            contents: "Host work\n  IdentityFile ~/.ssh/secret\n".to_owned(),
+            mode: Some(0o600),
+        };
+        RunReport {
+            config: PathBuf::from("config.toml"),
+            dry_run: false,
+            started_at: timestamp(start),
+            finished_at: timestamp(end),
+            duration_ms: elapsed_ms(start, end),
+            success: true,
+            steps: vec![StepReport {
+                name: "ssh",
+                status: "applied",
+                started_at: timestamp(start),
+                finished_at: timestamp(end),
+                duration_ms: elapsed_ms(start, end),
+                error: Vec::new(),
+                planned: vec![write.clone()],
+                applied: vec![write],
+                commands: vec![CommandRecord {
+                    program: "git".to_owned(),
+                    args: vec!["--version".to_owned()],
+                    exit_code: Some(0),
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn reports_round_trip_through_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reports/run.json");
+        let report = report();
+        report.write_to(&path).unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert_eq!(written, report.to_json().unwrap());
+        let value: Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(value["started_at"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(value["duration_ms"], 1500);
+        let step = &value["steps"][0];
+        assert_eq!(step["name"], "ssh");
+        assert_eq!(step["status"], "applied");
+        assert!(step.get("error").is_none());
+        assert_eq!(
+            step["commands"],
+            json!([{ "program": "git", "args": ["--version"], "exit_code": 0 }])
+        );
+    }
+
+    #[test]
+    fn file_contents_are_summarized() {
+        let json = report().to_json().unwrap();
+        assert!(!json.contains("secret"), "{json}");
+
+        let value: Value = serde_json::from_str(&json).unwrap();
+        let contents = "Host work\n  IdentityFile ~/.ssh/secret\n";
+        assert_eq!(
+            value["steps"][0]["applied"][0],
+            json!({
+                "kind": "write_file",
+                "path": "/Users/test/.ssh/config",
+                "current": null,
+                "contents": { "bytes": contents.len(), "sha256": sha256_hex(contents.as_bytes()) },
+                "mode": 0o600,
+            })
+        );
+    }
+}
@@ -1,46 +1,178 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    path::PathBuf,
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use console::style;
-use dialoguer::Confirm;
+use dialoguer::MultiSelect;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use tracing::warn;
 
 use crate::{
+    account::{self, Account},
+    command::{AsUser, CommandRecord, RecordingRunner, SystemRunner},
     config::{Config, ConfigBundle},
-    steps::{self, StepContext, StepKind},
+    diff,
+    journal::{Journal, Revert},
+    report::{self, RunReport, StepReport},
+    state::{self, RunState},
+    steps::{self, Change, Step, StepContext},
     sudo::SudoHelper,
+    util::sha256_hex,
 };
 
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    /// Only compute and print the planned changes.
+    pub dry_run: bool,
+    /// Run every step even if an earlier one fails, then summarize.
+    pub keep_going: bool,
+    /// Skip the confirmation prompt.
+    pub assume_yes: bool,
+    /// Whether a terminal is attached for prompts.
+    pub interactive: bool,
+    /// Skip steps recorded as completed for the same config.
+    pub resume: bool,
+    /// Write a JSON report of the run to this path.
+    pub report: Option<PathBuf>,
+    /// Print the JSON report to stdout; human output moves to stderr.
+    pub json_output: bool,
+    /// Write files under this directory instead of onto the machine.
+    pub root_dir: Option<PathBuf>,
+}
+
+/// How a single step ended.
+pub enum StepStatus {
+    /// Dry run: the number of changes that would be made.
+    Planned(usize),
+    /// The number of changes applied.
+    Applied(usize),
+    Unchanged,
+    /// Completed by a previous run and skipped by `--resume`.
+    Skipped,
+    Failed(anyhow::Error),
+}
+
+impl StepStatus {
+    fn label(&self) -> &'static str {
+        match self {
+            StepStatus::Planned(_) => "planned",
+            StepStatus::Applied(_) => "applied",
+            StepStatus::Unchanged => "unchanged",
+            StepStatus::Skipped => "skipped",
+            StepStatus::Failed(_) => "failed",
+        }
+    }
+}
+
+pub struct StepOutcome {
+    pub step: &'static dyn Step,
+    pub status: StepStatus,
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    pub planned: Vec<Change>,
+    pub applied: Vec<Change>,
+    pub commands: Vec<CommandRecord>,
+}
+
+impl StepOutcome {
+    fn skipped(step: &'static dyn Step) -> Self {
+        let now = SystemTime::now();
+        StepOutcome {
+            step,
+            status: StepStatus::Skipped,
+            started_at: now,
+            finished_at: now,
+            planned: Vec::new(),
+            applied: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    fn to_report(&self) -> StepReport {
+        let error = match &self.status {
+            StepStatus::Failed(err) => err.chain().map(|cause| cause.to_string()).collect(),
+            _ => Vec::new(),
+        };
+        StepReport {
+            name: self.step.name(),
+            status: self.status.label(),
+            started_at: report::timestamp(self.started_at),
+            finished_at: report::timestamp(self.finished_at),
+            duration_ms: report::elapsed_ms(self.started_at, self.finished_at),
+            error,
+            planned: self.planned.clone(),
+            applied: self.applied.clone(),
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+/// Changes a step planned and how many of them were applied before it
+/// finished or failed.
+#[derive(Default)]
+struct StepChanges {
+    planned: Vec<Change>,
+    applied: Vec<Change>,
+}
+
 pub struct Runner {
     config: Config,
+    config_path: PathBuf,
     root: PathBuf,
+    config_hash: String,
+    state_path: PathBuf,
+    options: RunOptions,
+    cmd: Rc<RecordingRunner>,
+    /// Who user-level steps act as; resolved when a run or status starts.
+    account: Option<Account>,
 }
 
 impl Runner {
-    pub fn new(bundle: ConfigBundle) -> Self {
+    pub fn new(bundle: ConfigBundle, options: RunOptions) -> Self {
+        // Progress staged under a root dir says nothing about the machine.
+        let config_hash = match &options.root_dir {
+            Some(dir) => sha256_hex(format!("{}\0root:{}", bundle.hash, dir.display()).as_bytes()),
+            None => bundle.hash,
+        };
+        let state_path = state::state_file(&bundle.config.system.home_directory);
         Runner {
             config: bundle.config,
+            config_path: bundle.path,
             root: bundle.root,
+            config_hash,
+            state_path,
+            options,
+            cmd: Rc::new(RecordingRunner::new(SystemRunner)),
+            account: None,
         }
     }
 
-    pub fn run(&mut self, requested: Option<Vec<StepKind>>) -> Result<()> {
+    pub fn run(&mut self, requested: Option<Vec<&'static dyn Step>>) -> Result<()> {
         let steps = match requested {
             Some(list) if !list.is_empty() => list,
             _ => self.default_steps(),
         };
 
         if steps.is_empty() {
-            println!("{} No steps to run", style("↷").yellow());
+            self.notice(format!("{} No steps to run", style("↷").yellow()));
             return Ok(());
         }
+        self.resolve_account()?;
 
-        if !self.confirm_steps(&steps)? {
-            println!(
+        let steps = if self.options.dry_run {
+            steps
+        } else {
+            self.pick_steps(&steps)?
+        };
+        if steps.is_empty() {
+            self.notice(format!(
                 "{} {}",
                 style("↷").yellow(),
                 style("Stop all steps").yellow()
-            );
+            ));
             return Ok(());
         }
 
@@ -48,11 +180,31 @@ impl Runner {
         let spinner_style = ProgressStyle::with_template("{spinner:.green} {msg}")?
             .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"]);
 
-        let mut sudo = SudoHelper::default();
+        let started_at = SystemTime::now();
+        let mut sudo = SudoHelper::new(self.cmd.clone(), self.options.interactive);
+        let mut outcomes = Vec::with_capacity(steps.len());
+        let mut state = self.initial_state()?;
+        let mut journal = match self.options.root_dir {
+            Some(_) => Journal::staged(),
+            None => Journal::create(&self.config.system.home_directory),
+        };
+        journal.set_account(self.account.as_ref());
 
-        for step in steps {
+        for &step in &steps {
             let pb = mp.add(ProgressBar::new_spinner());
             pb.set_style(spinner_style.clone());
+
+            if state.is_completed(step.name()) {
+                pb.finish_with_message(format!(
+                    "{} {}{}",
+                    style("↷").yellow(),
+                    style(step.display_name()).bold(),
+                    style(" (completed in previous run)").dim()
+                ));
+                outcomes.push(StepOutcome::skipped(step));
+                continue;
+            }
+
             pb.enable_steady_tick(Duration::from_millis(120));
             pb.set_message(format!(
                 "{} {}",
@@ -60,89 +212,444 @@ impl Runner {
                 style(step.display_name()).bold()
             ));
 
+            let step_started = SystemTime::now();
+            let mut changes = StepChanges::default();
             sudo.set_prompt_ui(pb.clone());
-            let result = self.run_step(step, &mut sudo, pb.clone());
+            let result = self.run_step(
+                step,
+                &steps,
+                &mut sudo,
+                pb.clone(),
+                &mut journal,
+                &mut changes,
+            );
             sudo.clear_prompt_ui();
 
-            match result {
-                Ok(_) => pb.finish_with_message(format!(
-                    "{} {}",
-                    style("✔").green().bold(),
-                    style(step.display_name()).bold()
-                )),
+            let status = match result {
+                Ok(()) => {
+                    let count = changes.planned.len();
+                    let (status, note) = if self.options.dry_run {
+                        (StepStatus::Planned(count), format!(" ({count} planned)"))
+                    } else if count == 0 {
+                        (StepStatus::Unchanged, " (up to date)".to_owned())
+                    } else {
+                        (StepStatus::Applied(count), String::new())
+                    };
+                    if !self.options.dry_run {
+                        state.mark_completed(step.name());
+                        if let Err(err) = self.save_state(&state) {
+                            warn!("unable to record progress: {err:#}");
+                        }
+                    }
+                    pb.finish_with_message(format!(
+                        "{} {}{}",
+                        style("✔").green().bold(),
+                        style(step.display_name()).bold(),
+                        style(note).dim()
+                    ));
+                    status
+                }
                 Err(err) => {
                     pb.finish_with_message(format!(
                         "{} {}",
                         style("✖").red().bold(),
                         style(step.display_name()).bold()
                     ));
-                    return Err(err);
+                    StepStatus::Failed(err)
                 }
+            };
+            let failed = matches!(status, StepStatus::Failed(_));
+            outcomes.push(StepOutcome {
+                step,
+                status,
+                started_at: step_started,
+                finished_at: SystemTime::now(),
+                planned: changes.planned,
+                applied: changes.applied,
+                commands: self.cmd.take(),
+            });
+            if failed && !self.options.keep_going {
+                break;
             }
         }
 
+        self.write_report(started_at, &outcomes)?;
+
+        if let Some(run_id) = journal.run_id() {
+            self.notice(format!(
+                "{} Changes journaled as run {run_id}; `setup-my-mac undo` reverts them",
+                style("↺").cyan()
+            ));
+        }
+
+        if self.options.dry_run {
+            let planned: usize = outcomes
+                .iter()
+                .map(|outcome| match outcome.status {
+                    StepStatus::Planned(count) => count,
+                    _ => 0,
+                })
+                .sum();
+            self.notice(format!(
+                "{} Dry run: {planned} change(s) planned, nothing applied",
+                style("↷").yellow()
+            ));
+        }
+
+        if !self.options.keep_going {
+            if let Some(StepOutcome {
+                status: StepStatus::Failed(err),
+                ..
+            }) = outcomes.pop()
+            {
+                return Err(err);
+            }
+            return Ok(());
+        }
+
+        self.print_summary(&outcomes);
+        let failed = outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome.status, StepStatus::Failed(_)))
+            .count();
+        if failed > 0 {
+            bail!("{failed} of {} steps failed", outcomes.len());
+        }
+
         Ok(())
     }
 
-    fn run_step(&self, kind: StepKind, sudo: &mut SudoHelper, pb: ProgressBar) -> Result<()> {
+    /// Compares the machine with the config without changing anything.
+    /// Returns the number of out-of-sync items; planning errors count as
+    /// drift since the state could not be verified.
+    pub fn status(&mut self, requested: Option<Vec<&'static dyn Step>>) -> Result<usize> {
+        let steps = match requested {
+            Some(list) if !list.is_empty() => list,
+            _ => self.default_steps(),
+        };
+        self.resolve_account()?;
+
+        let mut sudo = SudoHelper::new(self.cmd.clone(), self.options.interactive);
+        let user_cmd = self.user_runner();
+        let mut drift = 0;
+        for &step in &steps {
+            let mut ctx = StepContext {
+                cfg: &self.config,
+                root: &self.root,
+                sudo: &mut sudo,
+                cmd: &user_cmd,
+                account: self.account.as_ref(),
+                stage: self.options.root_dir.as_deref(),
+                steps: &steps,
+                progress: ProgressBar::hidden(),
+            };
+            // Restarts only follow other changes; they are not drift themselves.
+            let planned = step.plan(&mut ctx).map(|changes| {
+                changes
+                    .into_iter()
+                    .filter(|change| !matches!(change, Change::RestartApp { .. }))
+                    .collect::<Vec<_>>()
+            });
+            match planned {
+                Ok(changes) if changes.is_empty() => println!(
+                    "{} {}",
+                    style("✔").green().bold(),
+                    style(step.display_name()).bold()
+                ),
+                Ok(changes) => {
+                    println!(
+                        "{} {} {}",
+                        style("≠").yellow().bold(),
+                        style(step.display_name()).bold(),
+                        style(format!("({} out of sync)", changes.len())).dim()
+                    );
+                    for change in &changes {
+                        println!("  {} {}", style("≠").yellow(), change);
+                    }
+                    drift += changes.len();
+                }
+                Err(err) => {
+                    println!(
+                        "{} {} {}",
+                        style("✖").red().bold(),
+                        style(step.display_name()).bold(),
+                        style(format!("{err:#}")).red()
+                    );
+                    drift += 1;
+                }
+            }
+        }
+
+        Ok(drift)
+    }
+
+    /// Progress carried over from the previous run when resuming, otherwise
+    /// a fresh state that replaces it.
+    fn initial_state(&self) -> Result<RunState> {
+        if self.options.resume {
+            match RunState::load(&self.state_path)? {
+                Some(state) if state.config_hash == self.config_hash => return Ok(state),
+                Some(_) => self.notice(format!(
+                    "{} Config changed since the last run, starting from the first step",
+                    style("↷").yellow()
+                )),
+                None => self.notice(format!(
+                    "{} No previous run recorded, starting from the first step",
+                    style("↷").yellow()
+                )),
+            }
+        }
+
+        Ok(RunState::new(&self.config_hash))
+    }
+
+    /// Saves progress, handing the file to the account a root run acts for
+    /// so its own `--resume` can pick it up.
+    fn save_state(&self, state: &RunState) -> Result<()> {
+        state.save(&self.state_path)?;
+        match &self.account {
+            Some(account) => steps::hand_over(
+                &self.state_path,
+                account,
+                &self.config.system.home_directory,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Plans the step and applies it unless this is a dry run, recording
+    /// each change in `changes` as it is planned and applied. Applied
+    /// changes are journaled so `undo` can revert them.
+    fn run_step(
+        &self,
+        step: &dyn Step,
+        steps: &[&'static dyn Step],
+        sudo: &mut SudoHelper,
+        pb: ProgressBar,
+        journal: &mut Journal,
+        changes: &mut StepChanges,
+    ) -> Result<()> {
+        let user_cmd = self.user_runner();
         let mut ctx = StepContext {
             cfg: &self.config,
             root: &self.root,
             sudo,
+            cmd: &user_cmd,
+            account: self.account.as_ref(),
+            stage: self.options.root_dir.as_deref(),
+            steps,
             progress: pb,
         };
 
-        match kind {
-            StepKind::System => steps::system::run(&mut ctx),
-            StepKind::Homebrew => steps::homebrew::run(&mut ctx),
-            StepKind::Dotfiles => steps::dotfiles::run(&mut ctx),
-            StepKind::Ssh => steps::ssh::run(&mut ctx),
-            StepKind::Git => steps::git::run(&mut ctx),
+        changes.planned = step.plan(&mut ctx)?;
+        // Diff everything up front: applying a backup moves the file a later
+        // copy would otherwise be compared against.
+        let diffs = changes
+            .planned
+            .iter()
+            .map(diff::for_change)
+            .collect::<Result<Vec<_>>>()?;
+        if self.options.dry_run {
+            for (change, diff) in changes.planned.iter().zip(&diffs) {
+                ctx.println(format!("  {} {}", style("+").green(), change));
+                print_diff(&ctx, diff.as_deref());
+            }
+            return Ok(());
         }
+
+        for (change, diff) in changes.planned.iter().zip(&diffs) {
+            print_diff(&ctx, diff.as_deref());
+            step.apply(&mut ctx, change)?;
+            changes.applied.push(change.clone());
+            if let Some(revert) = Revert::for_change(change) {
+                journal
+                    .record(step.name(), revert)
+                    .context("recording change in the undo journal")?;
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn default_steps(&self) -> Vec<StepKind> {
-        use StepKind::*;
-        let mut steps = vec![System];
+    fn write_report(&self, started_at: SystemTime, outcomes: &[StepOutcome]) -> Result<()> {
+        if self.options.report.is_none() && !self.options.json_output {
+            return Ok(());
+        }
+
+        let finished_at = SystemTime::now();
+        let report = RunReport {
+            config: self.config_path.clone(),
+            dry_run: self.options.dry_run,
+            started_at: report::timestamp(started_at),
+            finished_at: report::timestamp(finished_at),
+            duration_ms: report::elapsed_ms(started_at, finished_at),
+            success: !outcomes
+                .iter()
+                .any(|outcome| matches!(outcome.status, StepStatus::Failed(_))),
+            steps: outcomes.iter().map(StepOutcome::to_report).collect(),
+        };
 
-        if self.config.homebrew.enable {
-            steps.push(Homebrew);
+        if let Some(path) = &self.options.report {
+            report.write_to(path)?;
         }
-        if !self.config.user.dotfiles.is_empty() {
-            steps.push(Dotfiles);
+        if self.options.json_output {
+            println!("{}", report.to_json()?);
         }
-        if self.config.user.ssh.is_some() {
-            steps.push(Ssh);
+        Ok(())
+    }
+
+    /// Checks the invoking user against `primary_user`, and notes whether
+    /// user-level steps must act on their behalf.
+    fn resolve_account(&mut self) -> Result<()> {
+        // Staged files belong to whoever renders them.
+        if let Some(dir) = &self.options.root_dir {
+            self.notice(format!(
+                "{} Staging changes under {}",
+                style("ℹ").cyan(),
+                style(dir.display()).bold()
+            ));
+            return Ok(());
         }
-        if self
-            .config
-            .user
-            .git
-            .as_ref()
-            .map(|g| g.enable)
-            .unwrap_or(false)
-        {
-            steps.push(Git);
+        self.account = account::resolve(&self.config.system, self.cmd.as_ref())?;
+        if let Some(account) = &self.account {
+            self.notice(format!(
+                "{} Running as root; user-level changes are made as {}",
+                style("ℹ").cyan(),
+                style(&account.user).bold()
+            ));
         }
+        Ok(())
+    }
 
-        steps
+    /// Runs commands as the primary user.
+    fn user_runner(&self) -> AsUser<'_> {
+        AsUser::new(
+            self.cmd.as_ref(),
+            self.account.as_ref().map(|account| account.user.as_str()),
+        )
     }
 
-    fn confirm_steps(&self, kinds: &[StepKind]) -> Result<bool> {
-        if kinds.is_empty() {
-            return Ok(true);
+    /// Prints a human-facing line, keeping stdout clean for `--output json`.
+    fn notice(&self, message: impl std::fmt::Display) {
+        if self.options.json_output {
+            eprintln!("{message}");
+        } else {
+            println!("{message}");
         }
+    }
+
+    pub fn default_steps(&self) -> Vec<&'static dyn Step> {
+        steps::REGISTRY
+            .iter()
+            .copied()
+            .filter(|step| self.is_enabled(*step))
+            .collect()
+    }
+
+    /// Whether `step` runs by default: it has something configured and no
+    /// profile or base config lists it in `disabled_steps`.
+    fn is_enabled(&self, step: &dyn Step) -> bool {
+        step.is_enabled(&self.config)
+            && !self
+                .config
+                .disabled_steps
+                .iter()
+                .any(|name| name == step.name())
+    }
+
+    /// Lets the user tick which steps to run. Enabled steps are listed along
+    /// with anything requested via `--steps`; `preselected` starts ticked.
+    fn pick_steps(&self, preselected: &[&'static dyn Step]) -> Result<Vec<&'static dyn Step>> {
+        if self.options.assume_yes {
+            return Ok(preselected.to_vec());
+        }
+
+        if !self.options.interactive {
+            bail!("confirmation required but no terminal is attached; pass --yes to run non-interactively");
+        }
+
+        let is_preselected = |step: &dyn Step| preselected.iter().any(|p| p.name() == step.name());
+        let candidates: Vec<&'static dyn Step> = steps::REGISTRY
+            .iter()
+            .copied()
+            .filter(|step| self.is_enabled(*step) || is_preselected(*step))
+            .collect();
+
+        let items: Vec<String> = candidates
+            .iter()
+            .map(|step| {
+                format!(
+                    "{} {}",
+                    style(step.display_name()).bold(),
+                    style(format!("— {}", step.describe(&self.config))).dim()
+                )
+            })
+            .collect();
+        let defaults: Vec<bool> = candidates
+            .iter()
+            .map(|step| is_preselected(*step))
+            .collect();
 
-        let joined = kinds
+        let picked = MultiSelect::new()
+            .with_prompt("Select steps to run (space toggles, enter confirms)")
+            .items(&items)
+            .defaults(&defaults)
+            .interact()?;
+
+        Ok(picked.into_iter().map(|index| candidates[index]).collect())
+    }
+
+    fn print_summary(&self, outcomes: &[StepOutcome]) {
+        let width = outcomes
             .iter()
-            .map(|k| k.display_name())
-            .collect::<Vec<_>>()
-            .join(", ");
-        let prompt = format!("Run all steps ({})?", style(joined).bold());
-        Confirm::new()
-            .with_prompt(prompt)
-            .default(true)
-            .interact()
-            .map_err(Into::into)
+            .map(|outcome| outcome.step.display_name().len())
+            .max()
+            .unwrap_or(0);
+
+        self.notice(format!("\n{}", style("Summary").bold()));
+        for outcome in outcomes {
+            let name = format!("{:width$}", outcome.step.display_name());
+            match &outcome.status {
+                StepStatus::Planned(count) => self.notice(format!(
+                    "  {} {}  {count} change(s) planned",
+                    style("✔").green(),
+                    name
+                )),
+                StepStatus::Applied(count) => self.notice(format!(
+                    "  {} {}  {count} change(s) applied",
+                    style("✔").green(),
+                    name
+                )),
+                StepStatus::Unchanged => {
+                    self.notice(format!("  {} {}  up to date", style("✔").green(), name))
+                }
+                StepStatus::Skipped => self.notice(format!(
+                    "  {} {}  completed in previous run",
+                    style("↷").yellow(),
+                    name
+                )),
+                StepStatus::Failed(err) => {
+                    self.notice(format!(
+                        "  {} {}  {}",
+                        style("✖").red(),
+                        name,
+                        style(err).red()
+                    ));
+                    for cause in err.chain().skip(1) {
+                        self.notice(format!("  {:width$}    caused by: {cause}", ""));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Prints a rendered diff under the step's progress bar, indented to sit
+/// beneath the change it belongs to.
+fn print_diff(ctx: &StepContext<'_>, diff: Option<&str>) {
+    if let Some(diff) = diff {
+        for line in diff.lines() {
+            ctx.println(format!("    {line}"));
+        }
     }
 }
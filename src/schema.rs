@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use schemars::JsonSchema;
+
+use crate::config::Config;
+
+// The shape of a config file on disk: `Config` plus the keys the loader
+// consumes before deserializing it. Only used to generate the schema.
+/// Configuration for setup-my-mac.
+#[derive(JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(title = "setup-my-mac configuration")]
+#[allow(dead_code)]
+struct ConfigFile {
+    /// Other config files (paths or globs) merged underneath this one.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Values for `{{ name }}` placeholders in string fields.
+    #[serde(default)]
+    vars: HashMap<String, VarValue>,
+    /// Dotted key paths of arrays that replace, rather than extend, the
+    /// ones from included files.
+    #[serde(default)]
+    replace: Vec<String>,
+    /// Named overlays applied with `--profile` or when `match` fits.
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+    #[serde(flatten)]
+    config: Config,
+}
+
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum VarValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+/// Any config keys, merged onto the base config when the profile applies.
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct Profile {
+    /// Apply this profile automatically when every criterion holds.
+    #[serde(rename = "match")]
+    criteria: Option<ProfileMatch>,
+    #[serde(flatten)]
+    overlay: HashMap<String, serde_json::Value>,
+}
+
+#[derive(JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+struct ProfileMatch {
+    /// Glob compared with the full and short host name.
+    hostname: Option<String>,
+    /// User running setup-my-mac.
+    primary_user: Option<String>,
+}
+
+/// JSON Schema for `config.toml`, pretty-printed with a trailing newline.
+pub fn config_schema() -> String {
+    let schema = schemars::schema_for!(ConfigFile);
+    let mut json = serde_json::to_string_pretty(&schema).expect("schemas serialize to JSON");
+    json.push('\n');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKED_IN: &str = include_str!("../config.schema.json");
+
+    #[test]
+    fn checked_in_schema_is_current() {
+        assert!(
+            config_schema() == CHECKED_IN,
+            "config.schema.json is out of date; regenerate it with \
+             `cargo run -- schema > config.schema.json`"
+        );
+    }
+
+    #[test]
+    fn example_config_keys_are_described() {
+        let schema: serde_json::Value = serde_json::from_str(&config_schema()).unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        for key in ["include", "vars", "profiles", "system", "homebrew", "user"] {
+            assert!(properties.contains_key(key), "missing {key}");
+        }
+        assert_eq!(schema["additionalProperties"], false);
+        assert_eq!(
+            schema["$defs"]["GitMerge"]["properties"]["conflictstyle"]["enum"],
+            serde_json::json!(["merge", "diff3", "zdiff3"])
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Progress of the most recent run, used by `--resume`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RunState {
+    /// Hash of the configuration the steps were completed for.
+    pub config_hash: String,
+    /// Names of the steps that finished successfully, in order.
+    #[serde(default)]
+    pub completed: Vec<String>,
+}
+
+impl RunState {
+    pub fn new(config_hash: &str) -> Self {
+        RunState {
+            config_hash: config_hash.to_owned(),
+            completed: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let state = toml::from_str(&data)
+            .with_context(|| format!("Invalid state in {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let data = toml::to_string(self).context("serializing run state")?;
+        fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn is_completed(&self, step: &str) -> bool {
+        self.completed.iter().any(|name| name == step)
+    }
+
+    pub fn mark_completed(&mut self, step: &str) {
+        if !self.is_completed(step) {
+            self.completed.push(step.to_owned());
+        }
+    }
+}
+
+/// `$XDG_STATE_HOME/setup-my-mac`, falling back to `.local/state/setup-my-mac`
+/// under `home`, the configured home directory. A root run on the primary
+/// user's behalf thus shares its state with that user's own runs.
+pub fn state_dir(home: &Path) -> PathBuf {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home.join(".local/state"));
+    base.join("setup-my-mac")
+}
+
+pub fn state_file(home: &Path) -> PathBuf {
+    state_dir(home).join("state.toml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/state.toml");
+
+        let mut state = RunState::new("abc");
+        state.mark_completed("system");
+        state.mark_completed("system");
+        state.save(&path).unwrap();
+
+        let loaded = RunState::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.config_hash, "abc");
+        assert_eq!(loaded.completed, ["system"]);
+        assert!(RunState::load(&dir.path().join("missing.toml"))
+            .unwrap()
+            .is_none());
+    }
+}
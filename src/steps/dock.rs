@@ -0,0 +1,429 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use console::Emoji;
+
+use super::{
+    system::{self, plan_defaults},
+    Change, Step, StepContext,
+};
+use crate::{
+    config::{Config, DockConfig},
+    defaults::{self, DefaultsValue, Key},
+    util::{file_url, percent_decode},
+};
+
+const ANCHOR: Emoji<'_, '_> = Emoji("⚓", "dock");
+
+pub const DOMAIN: &str = "com.apple.dock";
+
+pub struct DockStep;
+
+impl Step for DockStep {
+    fn name(&self) -> &'static str {
+        "dock"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Dock"
+    }
+
+    fn is_enabled(&self, cfg: &Config) -> bool {
+        cfg.system.dock.is_some()
+    }
+
+    fn describe(&self, cfg: &Config) -> String {
+        let Some(dock) = &cfg.system.dock else {
+            return "nothing configured".to_owned();
+        };
+        let mut parts = vec![format!("{} preferences", settings(dock).len())];
+        if let Some(apps) = &dock.apps {
+            parts.push(format!("{} apps", apps.len()));
+        }
+        if let Some(folders) = &dock.folders {
+            parts.push(format!("{} folders", folders.len()));
+        }
+        parts.join(", ")
+    }
+
+    fn plan(&self, ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+        plan(ctx)
+    }
+
+    fn apply(&self, ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+        apply(ctx, change)
+    }
+}
+
+pub fn plan(ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    let Some(dock) = ctx.cfg.system.dock.as_ref() else {
+        ctx.status("No Dock settings configured, skipping");
+        return Ok(changes);
+    };
+    if ctx.stage.is_some() {
+        ctx.info("--root-dir: skipping Dock settings, defaults cannot be staged");
+        return Ok(changes);
+    }
+
+    // `[[system.defaults]]` entries for the Dock are applied here rather
+    // than by the system step, and win over the settings above.
+    let mut desired = settings(dock);
+    let entries = system::defaults_entries(&ctx.cfg.system)?;
+    desired.extend(entries.into_iter().filter(|(key, _)| key.domain == DOMAIN));
+
+    let mut reader = defaults::Reader::new(ctx.cmd);
+    for (key, value) in system::dedup_defaults(desired) {
+        changes.extend(plan_defaults(&mut reader, key, value)?);
+    }
+    if let Some(apps) = &dock.apps {
+        changes.extend(plan_tiles(ctx, &mut reader, "persistent-apps", apps)?);
+    }
+    if let Some(folders) = &dock.folders {
+        changes.extend(plan_tiles(ctx, &mut reader, "persistent-others", folders)?);
+    }
+
+    // The Dock only reads its preferences at launch.
+    if !changes.is_empty() {
+        changes.push(Change::RestartApp {
+            app: "Dock".to_owned(),
+        });
+    }
+    Ok(changes)
+}
+
+pub fn apply(ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+    match change {
+        Change::Defaults {
+            key,
+            current_host,
+            desired,
+            ..
+        } => {
+            ctx.status(format!("{ANCHOR} updating Dock {key}"));
+            let key = Key {
+                current_host: *current_host,
+                ..dock_key(key)
+            };
+            defaults::write(ctx.cmd, key, desired)
+        }
+        Change::RestartApp { app } => {
+            ctx.info(format!("{ANCHOR} restarting the {app}"));
+            defaults::restart(ctx.cmd, app)
+        }
+        other => unreachable!("dock step cannot apply {other}"),
+    }
+}
+
+fn dock_key(key: &str) -> Key<'_> {
+    Key {
+        domain: DOMAIN,
+        key,
+        current_host: false,
+    }
+}
+
+/// The scalar preferences set in `dock`, by their `com.apple.dock` key.
+fn settings(dock: &DockConfig) -> Vec<(Key<'static>, DefaultsValue)> {
+    let string = |value: &str| DefaultsValue::String(value.to_owned());
+    [
+        ("autohide", dock.autohide.map(DefaultsValue::Bool)),
+        ("tilesize", dock.tile_size.map(DefaultsValue::Int)),
+        ("magnification", dock.magnification.map(DefaultsValue::Bool)),
+        ("largesize", dock.large_size.map(DefaultsValue::Int)),
+        (
+            "orientation",
+            dock.orientation.map(|value| string(value.as_str())),
+        ),
+        ("show-recents", dock.show_recents.map(DefaultsValue::Bool)),
+        (
+            "mineffect",
+            dock.minimize_effect.map(|value| string(value.as_str())),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((dock_key(key), value?)))
+    .collect()
+}
+
+/// Replaces the `persistent-apps` or `persistent-others` tiles when they do
+/// not point at `entries`, in order. Tiles are compared by path only: the
+/// Dock adds its own bookkeeping keys to every tile it loads.
+fn plan_tiles(
+    ctx: &StepContext<'_>,
+    reader: &mut defaults::Reader<'_>,
+    key: &'static str,
+    entries: &[String],
+) -> Result<Option<Change>> {
+    let paths = entries
+        .iter()
+        .map(|entry| {
+            ctx.resolve_path(entry, ctx.root)
+                .with_context(|| format!("resolving Dock entry {entry}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let current = reader.read(dock_key(key))?;
+    let current_paths = match &current {
+        Some(DefaultsValue::Array(tiles)) => tiles.iter().map(tile_path).collect(),
+        _ => Vec::new(),
+    };
+    let desired_paths: Vec<Option<PathBuf>> = paths.iter().cloned().map(Some).collect();
+    if current_paths == desired_paths {
+        return Ok(None);
+    }
+
+    let tile_type = if key == "persistent-apps" {
+        "file-tile"
+    } else {
+        "directory-tile"
+    };
+    let tiles = paths.iter().map(|path| tile(tile_type, path)).collect();
+    Ok(Some(Change::Defaults {
+        domain: DOMAIN.to_owned(),
+        key: key.to_owned(),
+        current_host: false,
+        current,
+        desired: DefaultsValue::Array(tiles),
+    }))
+}
+
+/// A Dock tile for the app bundle or folder at `path`.
+fn tile(tile_type: &str, path: &Path) -> DefaultsValue {
+    let label = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut data = dict([
+        (
+            "file-data",
+            dict([
+                ("_CFURLString", DefaultsValue::String(file_url(path))),
+                ("_CFURLStringType", DefaultsValue::Int(15)),
+            ]),
+        ),
+        ("file-label", DefaultsValue::String(label)),
+    ]);
+    if tile_type == "directory-tile" {
+        if let DefaultsValue::Dict(data) = &mut data {
+            data.insert("file-type".to_owned(), DefaultsValue::Int(2));
+        }
+    }
+    dict([
+        ("tile-data", data),
+        ("tile-type", DefaultsValue::String(tile_type.to_owned())),
+    ])
+}
+
+/// The path a tile points at, if it points at a file at all (spacers and
+/// the like do not).
+fn tile_path(tile: &DefaultsValue) -> Option<PathBuf> {
+    let url = get(get(get(tile, "tile-data")?, "file-data")?, "_CFURLString")?;
+    let DefaultsValue::String(url) = url else {
+        return None;
+    };
+    let path = url.strip_prefix("file://")?;
+    let path = percent_decode(path.trim_end_matches('/'));
+    Some(PathBuf::from(path))
+}
+
+fn get<'a>(value: &'a DefaultsValue, key: &str) -> Option<&'a DefaultsValue> {
+    match value {
+        DefaultsValue::Dict(dict) => dict.get(key),
+        _ => None,
+    }
+}
+
+fn dict<const N: usize>(entries: [(&str, DefaultsValue); N]) -> DefaultsValue {
+    DefaultsValue::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{command::ScriptedRunner, steps::testing};
+
+    const EXPORT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>autohide</key>
+	<true/>
+	<key>persistent-apps</key>
+	<array>
+		<dict>
+			<key>GUID</key>
+			<integer>1234</integer>
+			<key>tile-data</key>
+			<dict>
+				<key>bundle-identifier</key>
+				<string>com.apple.Safari</string>
+				<key>file-data</key>
+				<dict>
+					<key>_CFURLString</key>
+					<string>file:///Applications/Safari.app/</string>
+					<key>_CFURLStringType</key>
+					<integer>15</integer>
+				</dict>
+			</dict>
+			<key>tile-type</key>
+			<string>file-tile</string>
+		</dict>
+		<dict>
+			<key>tile-data</key>
+			<dict>
+				<key>file-data</key>
+				<dict>
+					<key>_CFURLString</key>
+					<string>file:///Applications/Visual%20Studio%20Code.app/</string>
+				</dict>
+			</dict>
+		</dict>
+	</array>
+</dict>
+</plist>
+"#;
+
+    fn dock_config(extra: &str) -> Config {
+        testing::config(&format!("[system.dock]\n{extra}"))
+    }
+
+    #[test]
+    fn matching_dock_is_left_alone() {
+        let cfg = dock_config(
+            r#"
+autohide = true
+apps = ["/Applications/Safari.app", "/Applications/Visual Studio Code.app"]
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner.on("/usr/bin/defaults export com.apple.dock -", 0, EXPORT);
+
+        let changes = testing::with_context(&cfg, Path::new("."), runner, plan).unwrap();
+        assert_eq!(changes, []);
+    }
+
+    #[test]
+    fn changes_end_with_a_single_restart() {
+        let cfg = dock_config(
+            r#"
+autohide = false
+tile_size = 48
+orientation = "left"
+apps = ["/Applications/Safari.app"]
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner.on("/usr/bin/defaults export com.apple.dock -", 0, EXPORT);
+
+        let changes = testing::with_context(&cfg, Path::new("."), runner.clone(), plan).unwrap();
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "defaults write com.apple.dock autohide -bool false (currently true)",
+                "defaults write com.apple.dock tilesize -int 48 (currently unset)",
+                "defaults write com.apple.dock orientation -string \"left\" (currently unset)",
+                "defaults write com.apple.dock persistent-apps -array [{ tile-data = { file-data = { _CFURLString = \"file:///Applications/Safari.app/\", _CFURLStringType = 15 }, file-label = \"Safari\" }, tile-type = \"file-tile\" }] (currently [{ GUID = 1234, tile-data = { bundle-identifier = \"com.apple.Safari\", file-data = { _CFURLString = \"file:///Applications/Safari.app/\", _CFURLStringType = 15 } }, tile-type = \"file-tile\" }, { tile-data = { file-data = { _CFURLString = \"file:///Applications/Visual%20Studio%20Code.app/\" } } }])",
+                "restart Dock",
+            ]
+        );
+
+        runner.on("/usr/bin/killall Dock", 1, "");
+        testing::with_context(&cfg, Path::new("."), runner.clone(), |ctx| {
+            apply(ctx, changes.last().unwrap())
+        })
+        .unwrap();
+        assert_eq!(runner.calls().last().unwrap(), "/usr/bin/killall Dock");
+    }
+
+    #[test]
+    fn dock_system_defaults_share_the_restart() {
+        let cfg = dock_config(
+            r#"
+autohide = false
+tile_size = 48
+
+[[system.defaults]]
+domain = "com.apple.dock"
+key = "tilesize"
+type = "int"
+value = 36
+
+[[system.defaults]]
+domain = "com.apple.dock"
+key = "show-recents"
+type = "bool"
+value = false
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner.on("/usr/bin/defaults export com.apple.dock -", 0, EXPORT);
+
+        let mut changes =
+            testing::with_context(&cfg, Path::new("."), runner.clone(), system::plan).unwrap();
+        changes.extend(testing::with_context(&cfg, Path::new("."), runner, plan).unwrap());
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "defaults write com.apple.dock autohide -bool false (currently true)",
+                "defaults write com.apple.dock tilesize -int 36 (currently unset)",
+                "defaults write com.apple.dock show-recents -bool false (currently unset)",
+                "restart Dock",
+            ]
+        );
+    }
+
+    #[test]
+    fn system_step_keeps_dock_defaults_when_the_dock_step_is_not_run() {
+        let cfg = dock_config(
+            r#"
+autohide = false
+
+[[system.defaults]]
+domain = "com.apple.dock"
+key = "show-recents"
+type = "bool"
+value = false
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner.on("/usr/bin/defaults export com.apple.dock -", 0, EXPORT);
+
+        let changes =
+            testing::with_steps(&cfg, &[&system::SystemStep], runner, system::plan).unwrap();
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "defaults write com.apple.dock show-recents -bool false (currently unset)",
+                "restart Dock",
+            ]
+        );
+    }
+
+    #[test]
+    fn folder_tiles_round_trip_through_urls() {
+        let path = Path::new("/Users/test/My Downloads");
+        let tile = tile("directory-tile", path);
+        assert_eq!(
+            get(
+                get(get(&tile, "tile-data").unwrap(), "file-data").unwrap(),
+                "_CFURLString"
+            ),
+            Some(&DefaultsValue::String(
+                "file:///Users/test/My%20Downloads/".to_owned()
+            ))
+        );
+        assert_eq!(tile_path(&tile), Some(path.to_owned()));
+    }
+}
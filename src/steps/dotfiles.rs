@@ -4,60 +4,126 @@ use std::path::{Path, PathBuf};
 use anyhow::{bail, Context, Result};
 use console::Emoji;
 
-use super::StepContext;
-use crate::util::resolve_path;
+use super::{Change, Step, StepContext};
+use crate::config::Config;
 
 const LINK: Emoji<'_, '_> = Emoji("🔗", "link");
 
-pub fn run(ctx: &mut StepContext<'_>) -> Result<()> {
+pub struct DotfilesStep;
+
+impl Step for DotfilesStep {
+    fn name(&self) -> &'static str {
+        "dotfiles"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Dotfiles"
+    }
+
+    fn is_enabled(&self, cfg: &Config) -> bool {
+        !cfg.user.dotfiles.is_empty()
+    }
+
+    fn describe(&self, cfg: &Config) -> String {
+        let mut names: Vec<_> = cfg.user.dotfiles.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        format!("sync {}", names.join(", "))
+    }
+
+    fn plan(&self, ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+        plan(ctx)
+    }
+
+    fn apply(&self, ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+        apply(ctx, change)
+    }
+}
+
+pub fn plan(ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
     if ctx.cfg.user.dotfiles.is_empty() {
         ctx.status("No dotfiles requested, skipping module");
-        return Ok(());
+        return Ok(changes);
     }
 
     for (name, entry) in ctx.cfg.user.dotfiles.iter() {
-        ctx.info(format!("{LINK} syncing dotfile {name}"));
-        sync_entry(ctx, entry)?;
+        changes.extend(
+            plan_entry(ctx, entry.root.as_deref().unwrap_or(ctx.root), entry)
+                .with_context(|| format!("planning dotfile {name}"))?,
+        );
     }
 
-    Ok(())
+    Ok(changes)
 }
 
-fn sync_entry(ctx: &mut StepContext<'_>, entry: &crate::config::DotfileEntry) -> Result<()> {
-    let source = resolve_path(&entry.source, ctx.root)
+pub fn apply(ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+    match change {
+        Change::Backup { path, backup } => fs::rename(path, backup)
+            .with_context(|| format!("renaming {} -> {}", path.display(), backup.display())),
+        Change::CopyFile { source, target } => {
+            ctx.info(format!("{LINK} syncing {}", target.display()));
+            copy_file(source, target)?;
+            ctx.take_ownership(target)
+        }
+        other => unreachable!("dotfiles step cannot apply {other}"),
+    }
+}
+
+fn plan_entry(
+    ctx: &StepContext<'_>,
+    root: &Path,
+    entry: &crate::config::DotfileEntry,
+) -> Result<Vec<Change>> {
+    let source = ctx
+        .resolve_path(&entry.source, root)
         .with_context(|| format!("resolving {}", entry.source))?;
-    let target = resolve_path(&entry.target, ctx.root)
+    let target = ctx
+        .resolve_target(&entry.target, root)
         .with_context(|| format!("resolving {}", entry.target))?;
 
     if !source.exists() {
         bail!("source {} does not exist", source.display());
     }
 
+    let mut files = Vec::new();
     if source.is_dir() {
-        if target.exists() {
-            backup_existing(&target)?;
-        }
-        sync_dir(&source, &target)
+        collect_files(&source, &source, &target, &mut files)?;
     } else {
-        if target.exists() {
-            if files_differ(&source, &target)? {
-                backup_existing(&target)?;
-            } else {
-                return Ok(());
-            }
-        }
-        copy_file(&source, &target)
+        files.push((source, target.clone()));
     }
-}
 
-fn sync_dir(source: &Path, target: &Path) -> Result<()> {
-    fs::create_dir_all(target)
-        .with_context(|| format!("creating directory {}", target.display()))?;
+    let mut stale = false;
+    for (source, dest) in &files {
+        if files_differ(source, dest)? {
+            stale = true;
+            break;
+        }
+    }
+    if !stale {
+        return Ok(Vec::new());
+    }
 
-    visit_dir(source, source, target)
+    let mut changes = Vec::new();
+    if target.exists() {
+        changes.push(Change::Backup {
+            backup: next_backup_path(&target)?,
+            path: target,
+        });
+    }
+    changes.extend(
+        files
+            .into_iter()
+            .map(|(source, target)| Change::CopyFile { source, target }),
+    );
+    Ok(changes)
 }
 
-fn visit_dir(current: &Path, source_root: &Path, target_root: &Path) -> Result<()> {
+fn collect_files(
+    current: &Path,
+    source_root: &Path,
+    target_root: &Path,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
     let entries = fs::read_dir(current)
         .with_context(|| format!("reading directory {}", current.display()))?;
     for entry in entries {
@@ -72,21 +138,15 @@ fn visit_dir(current: &Path, source_root: &Path, target_root: &Path) -> Result<(
             .file_type()
             .with_context(|| format!("getting file type for {}", path.display()))?;
         if file_type.is_dir() {
-            fs::create_dir_all(&dest)
-                .with_context(|| format!("creating directory {}", dest.display()))?;
-            visit_dir(&path, source_root, target_root)?;
+            collect_files(&path, source_root, target_root, files)?;
         } else {
-            copy_file(&path, &dest)?;
+            files.push((path, dest));
         }
     }
     Ok(())
 }
 
 fn copy_file(source: &Path, target: &Path) -> Result<()> {
-    if target.exists() && !files_differ(source, target)? {
-        return Ok(());
-    }
-
     if let Some(parent) = target.parent() {
         fs::create_dir_all(parent)
             .with_context(|| format!("creating directory {}", parent.display()))?;
@@ -111,13 +171,6 @@ fn files_differ(a: &Path, b: &Path) -> Result<bool> {
     Ok(a_bytes != b_bytes)
 }
 
-fn backup_existing(target: &Path) -> Result<()> {
-    let backup = next_backup_path(target)?;
-    fs::rename(target, &backup)
-        .with_context(|| format!("renaming {} -> {}", target.display(), backup.display()))?;
-    Ok(())
-}
-
 fn next_backup_path(target: &Path) -> Result<PathBuf> {
     let parent = target
         .parent()
@@ -142,3 +195,140 @@ fn next_backup_path(target: &Path) -> Result<PathBuf> {
         attempt += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        command::ScriptedRunner,
+        config::{Config, DotfileEntry},
+        steps::testing,
+    };
+
+    fn entry(source: &Path, target: &Path) -> DotfileEntry {
+        DotfileEntry {
+            source: source.display().to_string(),
+            target: target.display().to_string(),
+            root: None,
+        }
+    }
+
+    fn plan_in(cfg: &Config, root: &Path, entry: &DotfileEntry) -> Result<Vec<Change>> {
+        testing::with_context(cfg, root, Rc::new(ScriptedRunner::new()), |ctx| {
+            plan_entry(ctx, root, entry)
+        })
+    }
+
+    fn plan(root: &Path, entry: &DotfileEntry) -> Result<Vec<Change>> {
+        plan_in(&testing::config(""), root, entry)
+    }
+
+    #[test]
+    fn plan_skips_identical_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("zshrc");
+        let target = dir.path().join(".zshrc");
+        fs::write(&source, "export A=1\n").unwrap();
+        fs::write(&target, "export A=1\n").unwrap();
+
+        let changes = plan(dir.path(), &entry(&source, &target)).unwrap();
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn plan_backs_up_differing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("zshrc");
+        let target = dir.path().join(".zshrc");
+        fs::write(&source, "export A=1\n").unwrap();
+        fs::write(&target, "export A=2\n").unwrap();
+
+        let changes = plan(dir.path(), &entry(&source, &target)).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                Change::Backup {
+                    path: target.clone(),
+                    backup: dir.path().join(".zshrc.bak"),
+                },
+                Change::CopyFile { source, target },
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_copies_whole_directory_when_target_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("nvim");
+        fs::create_dir_all(source.join("lua")).unwrap();
+        fs::write(source.join("init.lua"), "-- init\n").unwrap();
+        fs::write(source.join("lua/plugins.lua"), "return {}\n").unwrap();
+        let target = dir.path().join("config/nvim");
+
+        let changes = plan(dir.path(), &entry(&source, &target)).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|c| matches!(c, Change::CopyFile { .. })));
+    }
+
+    #[test]
+    fn tilde_expands_to_configured_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        fs::write(dir.path().join("zshrc"), "export A=1\n").unwrap();
+        let cfg: Config = toml::from_str(&format!(
+            "[system]\nhome_directory = {:?}\nprimary_user = \"test\"\n[user]\n",
+            home.display().to_string()
+        ))
+        .unwrap();
+        let entry = DotfileEntry {
+            source: "zshrc".to_owned(),
+            target: "~/.zshrc".to_owned(),
+            root: None,
+        };
+
+        let changes = plan_in(&cfg, dir.path(), &entry).unwrap();
+        assert_eq!(
+            changes,
+            vec![Change::CopyFile {
+                source: dir.path().join("zshrc"),
+                target: home.join(".zshrc"),
+            }]
+        );
+    }
+
+    #[test]
+    fn staged_run_writes_under_root_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let stage = dir.path().join("stage");
+        fs::create_dir_all(dir.path().join("nvim")).unwrap();
+        fs::write(dir.path().join("nvim/init.lua"), "vim.o.number = true\n").unwrap();
+        let cfg = testing::config(
+            r#"
+[user.dotfiles.nvim]
+source = "nvim"
+target = "~/.config/nvim"
+"#,
+        );
+
+        testing::staged(
+            &cfg,
+            dir.path(),
+            Some(&stage),
+            Rc::new(ScriptedRunner::new()),
+            |ctx| {
+                for change in super::plan(ctx)? {
+                    apply(ctx, &change)?;
+                }
+                Ok::<_, anyhow::Error>(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(stage.join("Users/test/.config/nvim/init.lua")).unwrap(),
+            "vim.o.number = true\n"
+        );
+    }
+}
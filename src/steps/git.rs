@@ -1,121 +1,260 @@
-use std::process::Command;
+use std::{fs, path::Path};
 
 use anyhow::{anyhow, Context, Result};
 use console::Emoji;
 use tracing::info;
 
-use super::StepContext;
+use super::{plan_write_file, write_file, Change, Step, StepContext};
 use crate::{
-    config::GitConfig,
-    util::{resolve_path, write_if_changed},
+    command::CommandRunner,
+    config::{Config, GitConfig},
 };
 
 const BRANCH: Emoji<'_, '_> = Emoji("🌿", "git");
 
-pub fn run(ctx: &mut StepContext<'_>) -> Result<()> {
+pub struct GitStep;
+
+impl Step for GitStep {
+    fn name(&self) -> &'static str {
+        "git"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Git"
+    }
+
+    fn is_enabled(&self, cfg: &Config) -> bool {
+        cfg.user.git.as_ref().map(|g| g.enable).unwrap_or(false)
+    }
+
+    fn describe(&self, cfg: &Config) -> String {
+        let ignores = cfg.user.git.as_ref().map_or(0, |git| git.ignores.len());
+        if ignores > 0 {
+            format!("git config --global keys and {ignores} global ignores")
+        } else {
+            "git config --global keys".to_owned()
+        }
+    }
+
+    fn plan(&self, ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+        plan(ctx)
+    }
+
+    fn apply(&self, ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+        apply(ctx, change)
+    }
+}
+
+pub fn plan(ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
     let Some(git_cfg) = ctx.cfg.user.git.as_ref() else {
         ctx.status("No git settings configured, skipping");
-        return Ok(());
+        return Ok(changes);
     };
 
     if !git_cfg.enable {
         ctx.status("user.git.enable is false, skipping git module");
-        return Ok(());
+        return Ok(changes);
     }
 
     if !has_git_work(git_cfg) {
         ctx.status("No git preferences provided, skipping");
-        return Ok(());
+        return Ok(changes);
     }
 
-    ctx.status(format!("{BRANCH} applying git config"));
+    // Staged runs write a `.gitconfig` under the root dir instead of the
+    // user's global config.
+    let file = match ctx.stage {
+        Some(_) => Some(ctx.resolve_target("~/.gitconfig", ctx.root)?),
+        None => None,
+    };
+    let file = file.as_deref();
 
     if let Some(name) = &git_cfg.user_name {
-        ensure_git_config("user.name", name)?;
+        changes.extend(plan_git_config(ctx.cmd, file, "user.name", name)?);
     }
     if let Some(email) = &git_cfg.user_email {
-        ensure_git_config("user.email", email)?;
+        changes.extend(plan_git_config(ctx.cmd, file, "user.email", email)?);
     }
     if let Some(helper) = &git_cfg.credential_helper {
-        ensure_git_config("credential.helper", helper)?;
+        changes.extend(plan_git_config(ctx.cmd, file, "credential.helper", helper)?);
     }
     if let Some(init) = &git_cfg.init {
         if let Some(branch) = &init.default_branch {
-            ensure_git_config("init.defaultBranch", branch)?;
+            changes.extend(plan_git_config(
+                ctx.cmd,
+                file,
+                "init.defaultBranch",
+                branch,
+            )?);
         }
     }
     if let Some(merge) = &git_cfg.merge {
         if let Some(style) = &merge.conflictstyle {
-            ensure_git_config("merge.conflictStyle", style)?;
+            changes.extend(plan_git_config(
+                ctx.cmd,
+                file,
+                "merge.conflictStyle",
+                style,
+            )?);
         }
     }
     if let Some(pull) = &git_cfg.pull {
         if let Some(rebase) = pull.rebase {
-            ensure_git_config("pull.rebase", if rebase { "true" } else { "false" })?;
+            changes.extend(plan_git_config(
+                ctx.cmd,
+                file,
+                "pull.rebase",
+                if rebase { "true" } else { "false" },
+            )?);
         }
     }
     if let Some(push) = &git_cfg.push {
         if let Some(auto) = push.auto_setup_remote {
-            ensure_git_config("push.autoSetupRemote", if auto { "true" } else { "false" })?;
+            changes.extend(plan_git_config(
+                ctx.cmd,
+                file,
+                "push.autoSetupRemote",
+                if auto { "true" } else { "false" },
+            )?);
         }
     }
 
     if !git_cfg.ignores.is_empty() {
-        ensure_global_ignore(ctx, &git_cfg.ignores)?;
+        changes.extend(plan_global_ignore(ctx, file, &git_cfg.ignores)?);
     }
 
-    Ok(())
+    Ok(changes)
+}
+
+pub fn apply(ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+    ctx.status(format!("{BRANCH} applying git config"));
+
+    match change {
+        Change::GitConfig {
+            file, key, desired, ..
+        } => {
+            if let Some(parent) = file.as_deref().and_then(Path::parent) {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("creating {}", parent.display()))?;
+            }
+            set_git_config(ctx.cmd, file.as_deref(), key, desired)
+        }
+        Change::WriteFile { path, contents, .. } => {
+            write_file(path, contents, None)?;
+            ctx.take_ownership(path)?;
+            info!("updated global gitignore at {}", path.display());
+            Ok(())
+        }
+        other => unreachable!("git step cannot apply {other}"),
+    }
 }
 
-fn ensure_git_config(key: &str, value: &str) -> Result<()> {
-    if git_get(key)?.as_deref() == Some(value) {
+fn plan_git_config(
+    cmd: &dyn CommandRunner,
+    file: Option<&Path>,
+    key: &str,
+    value: &str,
+) -> Result<Option<Change>> {
+    let current = git_get(cmd, file, key)?;
+    if current.as_deref() == Some(value) {
         info!("git {key} already set");
-        return Ok(());
+        return Ok(None);
     }
 
-    let status = Command::new("git")
-        .args(["config", "--global", key, value])
-        .status()
+    Ok(Some(Change::GitConfig {
+        file: file.map(Path::to_path_buf),
+        key: key.to_owned(),
+        current,
+        desired: value.to_owned(),
+    }))
+}
+
+pub fn set_git_config(
+    cmd: &dyn CommandRunner,
+    file: Option<&Path>,
+    key: &str,
+    value: &str,
+) -> Result<()> {
+    let output = cmd
+        .output("git", &config_args(file, &[key, value])?)
         .with_context(|| format!("setting git {key}"))?;
-    if status.success() {
+    if output.success() {
         Ok(())
     } else {
-        Err(anyhow!("git config for {key} failed"))
+        Err(anyhow!("git config for {key} failed: {}", output.stderr))
     }
 }
 
-fn git_get(key: &str) -> Result<Option<String>> {
-    let output = Command::new("git")
-        .args(["config", "--global", "--get", key])
-        .output()
-        .with_context(|| format!("reading git {key}"))?;
-    if output.status.success() {
-        Ok(Some(
-            String::from_utf8_lossy(&output.stdout).trim().to_string(),
-        ))
-    } else if output.status.code() == Some(1) {
-        Ok(None)
+pub fn unset_git_config(cmd: &dyn CommandRunner, file: Option<&Path>, key: &str) -> Result<()> {
+    let output = cmd
+        .output("git", &config_args(file, &["--unset", key])?)
+        .with_context(|| format!("unsetting git {key}"))?;
+    // Exit code 5 means the key was already absent.
+    if output.success() || output.code == Some(5) {
+        Ok(())
     } else {
         Err(anyhow!(
-            "git config --get {key} failed: {}",
-            String::from_utf8_lossy(&output.stderr)
+            "git config --unset {key} failed: {}",
+            output.stderr
         ))
     }
 }
 
-fn ensure_global_ignore(ctx: &StepContext<'_>, ignores: &[String]) -> Result<()> {
-    let path = resolve_path("~/.config/git/ignore", ctx.root)?;
+pub fn git_get(cmd: &dyn CommandRunner, file: Option<&Path>, key: &str) -> Result<Option<String>> {
+    let output = cmd
+        .output("git", &config_args(file, &["--get", key])?)
+        .with_context(|| format!("reading git {key}"))?;
+    if output.success() {
+        Ok(Some(output.stdout.trim().to_string()))
+    } else if output.code == Some(1) {
+        Ok(None)
+    } else {
+        Err(anyhow!("git config --get {key} failed: {}", output.stderr))
+    }
+}
+
+fn plan_global_ignore(
+    ctx: &StepContext<'_>,
+    file: Option<&Path>,
+    ignores: &[String],
+) -> Result<Vec<Change>> {
+    // core.excludesFile names the final location, even when staging.
+    let path = ctx.resolve_path("~/.config/git/ignore", ctx.root)?;
     let mut body = ignores.join("\n");
     body.push('\n');
-    let changed = write_if_changed(&path, &body)?;
-    if changed {
-        info!("updated global gitignore at {}", path.display());
-    }
+
+    let mut changes: Vec<Change> = plan_write_file(&ctx.target(&path), &body, None)?
+        .into_iter()
+        .collect();
 
     let path_str = path
         .to_str()
         .context("global ignore path contains invalid UTF-8")?;
-    ensure_git_config("core.excludesFile", path_str)
+    changes.extend(plan_git_config(
+        ctx.cmd,
+        file,
+        "core.excludesFile",
+        path_str,
+    )?);
+    Ok(changes)
+}
+
+/// `git config` arguments for `file`, or for the global config.
+fn config_args<'a>(file: Option<&'a Path>, args: &[&'a str]) -> Result<Vec<&'a str>> {
+    let mut all = vec!["config"];
+    match file {
+        Some(file) => {
+            all.push("--file");
+            all.push(
+                file.to_str()
+                    .context("git config path contains invalid UTF-8")?,
+            );
+        }
+        None => all.push("--global"),
+    }
+    all.extend_from_slice(args);
+    Ok(all)
 }
 
 fn has_git_work(cfg: &GitConfig) -> bool {
@@ -140,3 +279,93 @@ fn has_git_work(cfg: &GitConfig) -> bool {
             .is_some()
         || !cfg.ignores.is_empty()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::command::ScriptedRunner;
+    use crate::steps::testing;
+
+    #[test]
+    fn plan_only_includes_keys_that_differ() {
+        let cfg = testing::config(
+            r#"
+[user.git]
+enable = true
+user_name = "Jane"
+user_email = "jane@example.com"
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner
+            .on("git config --global --get user.name", 0, "Jane\n")
+            .on("git config --global --get user.email", 1, "");
+
+        let changes = testing::with_context(&cfg, Path::new("."), runner.clone(), plan).unwrap();
+
+        assert_eq!(
+            changes,
+            vec![Change::GitConfig {
+                file: None,
+                key: "user.email".to_owned(),
+                current: None,
+                desired: "jane@example.com".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn apply_sets_global_keys() {
+        let cfg = testing::config("");
+        let runner = Rc::new(ScriptedRunner::new());
+        runner.on("git config --global pull.rebase true", 0, "");
+
+        let change = Change::GitConfig {
+            file: None,
+            key: "pull.rebase".to_owned(),
+            current: Some("false".to_owned()),
+            desired: "true".to_owned(),
+        };
+        testing::with_context(&cfg, Path::new("."), runner.clone(), |ctx| {
+            apply(ctx, &change)
+        })
+        .unwrap();
+
+        assert_eq!(runner.calls(), ["git config --global pull.rebase true"]);
+    }
+
+    #[test]
+    fn staged_run_edits_a_gitconfig_under_root_dir() {
+        let stage = tempfile::tempdir().unwrap();
+        let gitconfig = stage.path().join("Users/test/.gitconfig");
+        let cfg = testing::config(
+            r#"
+[user.git]
+enable = true
+user_name = "Jane"
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner.on(
+            &format!("git config --file {} --get user.name", gitconfig.display()),
+            1,
+            "",
+        );
+
+        let changes =
+            testing::staged(&cfg, Path::new("."), Some(stage.path()), runner, plan).unwrap();
+
+        assert_eq!(
+            changes,
+            vec![Change::GitConfig {
+                file: Some(gitconfig),
+                key: "user.name".to_owned(),
+                current: None,
+                desired: "Jane".to_owned(),
+            }]
+        );
+    }
+}
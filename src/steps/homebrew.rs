@@ -1,79 +1,204 @@
+use std::collections::HashSet;
 use std::io::Write;
-use std::process::Command;
 
 use anyhow::{anyhow, Context, Result};
 use console::Emoji;
 use tempfile::NamedTempFile;
 use tracing::info;
 
-use super::StepContext;
-use crate::config::HomebrewConfig;
+use super::{hand_over, Change, Step, StepContext};
+use crate::{command::CommandRunner, config::Config};
 
 const PACKAGE: Emoji<'_, '_> = Emoji("📦", "[pkg]");
 
-pub fn run(ctx: &mut StepContext<'_>) -> Result<()> {
+pub struct HomebrewStep;
+
+impl Step for HomebrewStep {
+    fn name(&self) -> &'static str {
+        "homebrew"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Homebrew"
+    }
+
+    fn is_enabled(&self, cfg: &Config) -> bool {
+        cfg.homebrew.enable
+    }
+
+    fn describe(&self, cfg: &Config) -> String {
+        format!(
+            "brew bundle with {} brews and {} casks",
+            cfg.homebrew.brews.len(),
+            cfg.homebrew.casks.len()
+        )
+    }
+
+    fn plan(&self, ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+        plan(ctx)
+    }
+
+    fn apply(&self, ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+        apply(ctx, change)
+    }
+}
+
+pub fn plan(ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
     let hb = &ctx.cfg.homebrew;
     if !hb.enable {
         ctx.status("Homebrew disabled in config, skipping");
-        return Ok(());
+        return Ok(Vec::new());
+    }
+    if ctx.stage.is_some() {
+        ctx.info("--root-dir: skipping Homebrew, installs cannot be staged");
+        return Ok(Vec::new());
     }
 
-    ensure_brew_available()?;
+    ensure_brew_available(ctx.cmd)?;
 
     if hb.brews.is_empty() && hb.casks.is_empty() {
         ctx.status("No Homebrew packages configured, skipping");
-        return Ok(());
+        return Ok(Vec::new());
     }
 
-    ctx.status(format!("{PACKAGE} brew bundle"));
-    ensure_bundle(ctx, hb)
+    let installed_brews = brew_list(ctx.cmd, "--formula")?;
+    let installed_casks = brew_list(ctx.cmd, "--cask")?;
+    let brews = missing(&hb.brews, &installed_brews);
+    let casks = missing(&hb.casks, &installed_casks);
+
+    if brews.is_empty() && casks.is_empty() {
+        info!("all Homebrew packages already installed");
+        return Ok(Vec::new());
+    }
+
+    Ok(vec![Change::BrewBundle { brews, casks }])
+}
+
+pub fn apply(ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+    match change {
+        Change::BrewBundle { brews, casks } => {
+            ctx.status(format!("{PACKAGE} brew bundle"));
+            ensure_bundle(ctx, brews, casks)
+        }
+        other => unreachable!("homebrew step cannot apply {other}"),
+    }
 }
 
-fn ensure_brew_available() -> Result<()> {
-    let status = Command::new("brew")
-        .arg("--version")
-        .status()
+fn ensure_brew_available(cmd: &dyn CommandRunner) -> Result<()> {
+    let output = cmd
+        .output("brew", &["--version"])
         .context("failed to invoke brew")?;
-    if status.success() {
+    if output.success() {
         Ok(())
     } else {
         Err(anyhow!("brew is not available"))
     }
 }
 
-fn ensure_bundle(ctx: &StepContext<'_>, cfg: &HomebrewConfig) -> Result<()> {
+fn brew_list(cmd: &dyn CommandRunner, kind: &str) -> Result<HashSet<String>> {
+    let output = cmd
+        .output("brew", &["list", kind, "-1"])
+        .with_context(|| format!("failed to invoke brew list {kind}"))?;
+    if !output.success() {
+        return Err(anyhow!("brew list {kind} failed: {}", output.stderr));
+    }
+    Ok(output
+        .stdout
+        .lines()
+        .map(|line| line.trim().to_owned())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+/// Returns the requested packages that are not installed. Tap-qualified
+/// names such as `owner/tap/name` are matched on their final segment,
+/// which is how `brew list` reports them.
+fn missing(requested: &[String], installed: &HashSet<String>) -> Vec<String> {
+    requested
+        .iter()
+        .filter(|name| {
+            let short = name.rsplit('/').next().unwrap_or(name);
+            !installed.contains(short)
+        })
+        .cloned()
+        .collect()
+}
+
+fn ensure_bundle(ctx: &StepContext<'_>, brews: &[String], casks: &[String]) -> Result<()> {
     info!(
         "Preparing Brewfile with {} brews and {} casks",
-        cfg.brews.len(),
-        cfg.casks.len()
+        brews.len(),
+        casks.len()
     );
 
-    let brewfile_contents = render_brewfile(cfg);
-    let mut tmp = NamedTempFile::new().context("failed to create temporary Brewfile")?;
-    tmp.write_all(brewfile_contents.as_bytes())
-        .context("failed to write temporary Brewfile contents")?;
-
-    let mut command = Command::new("brew");
-    command.arg("bundle");
-    command.arg("--file");
-    command.arg(tmp.path());
+    let tmp = write_brewfile(ctx, &render_brewfile(brews, casks))?;
+    let brewfile = tmp.path().to_str().context("Brewfile path not utf8")?;
+    let output = ctx.stream_command("brew", &["bundle", "--file", brewfile], "brew bundle")?;
 
-    let status = ctx.stream_command(command, "brew bundle")?;
-
-    if status.success() {
+    if output.success() {
         Ok(())
     } else {
         Err(anyhow!("brew bundle failed"))
     }
 }
 
-fn render_brewfile(cfg: &HomebrewConfig) -> String {
+/// Writes the Brewfile where the user `brew` runs as can read it. Root's
+/// temp dir is private, so as root it goes to `/tmp`, owned by the account.
+fn write_brewfile(ctx: &StepContext<'_>, contents: &str) -> Result<NamedTempFile> {
+    let tmp = match ctx.account {
+        Some(_) => NamedTempFile::new_in("/tmp"),
+        None => NamedTempFile::new(),
+    };
+    let mut tmp = tmp.context("failed to create temporary Brewfile")?;
+    tmp.write_all(contents.as_bytes())
+        .context("failed to write temporary Brewfile contents")?;
+    if let Some(account) = ctx.account {
+        hand_over(tmp.path(), account, &ctx.cfg.system.home_directory)?;
+    }
+    Ok(tmp)
+}
+
+fn render_brewfile(brews: &[String], casks: &[String]) -> String {
     let mut contents = String::new();
-    for formula in &cfg.brews {
+    for formula in brews {
         contents.push_str(&format!("brew \"{}\"\n", formula));
     }
-    for cask in &cfg.casks {
+    for cask in casks {
         contents.push_str(&format!("cask \"{}\"\n", cask));
     }
     contents
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{account::Account, command::ScriptedRunner, steps::testing};
+
+    #[test]
+    fn brewfile_is_readable_by_the_account() {
+        // Handing the file to ourselves needs no privileges.
+        let dir = tempfile::tempdir().unwrap();
+        let me = fs::metadata(dir.path()).unwrap();
+        let account = Account {
+            user: "test".to_owned(),
+            uid: me.uid(),
+            gid: me.gid(),
+        };
+
+        let cfg = testing::config("");
+        let runner = Rc::new(ScriptedRunner::new());
+        let tmp = testing::as_account(&cfg, Path::new("."), &account, runner, |ctx| {
+            write_brewfile(ctx, &render_brewfile(&["git".to_owned()], &[]))
+        })
+        .unwrap();
+
+        assert!(tmp.path().starts_with("/tmp"));
+        assert_eq!(fs::metadata(tmp.path()).unwrap().uid(), account.uid);
+        assert_eq!(fs::read_to_string(tmp.path()).unwrap(), "brew \"git\"\n");
+    }
+}
@@ -1,46 +1,91 @@
+pub mod dock;
 pub mod dotfiles;
 pub mod git;
 pub mod homebrew;
+mod plan;
 pub mod ssh;
 pub mod system;
 
-use std::io::{BufRead, BufReader, Read};
-use std::path::Path;
-use std::process::{Command, ExitStatus, Stdio};
-use std::thread;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
-use clap::ValueEnum;
 use console::style;
 use indicatif::ProgressBar;
 
-use crate::{config::Config, sudo::SudoHelper};
-
-#[derive(Copy, Clone, Debug, ValueEnum, PartialEq, Eq, Hash)]
-pub enum StepKind {
-    System,
-    Homebrew,
-    Dotfiles,
-    Ssh,
-    Git,
-}
-
-impl StepKind {
-    pub fn display_name(&self) -> &'static str {
-        match self {
-            StepKind::System => "System",
-            StepKind::Homebrew => "Homebrew",
-            StepKind::Dotfiles => "Dotfiles",
-            StepKind::Ssh => "SSH",
-            StepKind::Git => "Git",
-        }
+use crate::{
+    account::Account,
+    command::{CommandOutput, CommandRunner, Stream},
+    config::Config,
+    sudo::SudoHelper,
+    util::{normalize_newlines, reroot, resolve_path_in_home},
+};
+
+pub use plan::Change;
+
+/// A module that can bring one area of the machine in line with the config.
+///
+/// New modules implement this trait and add themselves to [`REGISTRY`]; the
+/// runner and CLI discover them from there.
+pub trait Step: Sync {
+    /// Identifier used on the command line, e.g. `--steps homebrew`.
+    fn name(&self) -> &'static str;
+
+    fn display_name(&self) -> &'static str;
+
+    /// Whether the step runs when no `--steps` are given.
+    fn is_enabled(&self, cfg: &Config) -> bool;
+
+    /// One-line summary of what the step manages, shown in the step picker.
+    fn describe(&self, cfg: &Config) -> String;
+
+    /// Compares the desired config with the current machine state.
+    fn plan(&self, ctx: &mut StepContext<'_>) -> Result<Vec<Change>>;
+
+    /// Applies one change previously returned by [`Step::plan`].
+    fn apply(&self, ctx: &mut StepContext<'_>, change: &Change) -> Result<()>;
+}
+
+impl std::fmt::Debug for dyn Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
     }
 }
 
+/// All known steps, in the order they run by default.
+pub static REGISTRY: &[&dyn Step] = &[
+    &system::SystemStep,
+    &dock::DockStep,
+    &homebrew::HomebrewStep,
+    &dotfiles::DotfilesStep,
+    &ssh::SshStep,
+    &git::GitStep,
+];
+
+pub fn find(name: &str) -> Option<&'static dyn Step> {
+    REGISTRY.iter().copied().find(|step| step.name() == name)
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    REGISTRY.iter().map(|step| step.name())
+}
+
 pub struct StepContext<'cfg> {
     pub cfg: &'cfg Config,
     pub root: &'cfg Path,
     pub sudo: &'cfg mut SudoHelper,
+    /// Runs commands as the primary user.
+    pub cmd: &'cfg dyn CommandRunner,
+    /// Set when running as root on the primary user's behalf; files written
+    /// into their home are handed over to this account.
+    pub account: Option<&'cfg Account>,
+    /// `--root-dir`: files that would be written on the machine are written
+    /// under this directory instead.
+    pub stage: Option<&'cfg Path>,
+    /// Every step taking part in this run.
+    pub steps: &'cfg [&'static dyn Step],
     pub progress: ProgressBar,
 }
 
@@ -64,8 +109,17 @@ impl<'cfg> StepContext<'cfg> {
             StepLogLevel::Error => style("[error]").red().to_string(),
         };
 
-        self.progress
-            .println(format!("  {} {}", label, message.as_ref()));
+        self.println(format!("  {} {}", label, message.as_ref()));
+    }
+
+    /// Prints a line above the progress bar, or straight to stderr when the
+    /// bar is hidden because no terminal is attached.
+    pub fn println(&self, line: impl AsRef<str>) {
+        if self.progress.is_hidden() {
+            eprintln!("{}", line.as_ref());
+        } else {
+            self.progress.println(line.as_ref());
+        }
     }
 
     pub fn info(&self, message: impl AsRef<str>) {
@@ -82,64 +136,269 @@ impl<'cfg> StepContext<'cfg> {
         self.log(StepLogLevel::Error, message);
     }
 
-    pub fn stream_command(&self, mut command: Command, label: &str) -> Result<ExitStatus> {
-        command.stdin(Stdio::inherit());
-        command.stdout(Stdio::piped());
-        command.stderr(Stdio::piped());
+    /// Resolves a config path, expanding `~` to the configured home directory.
+    pub fn resolve_path(&self, input: &str, base_dir: &Path) -> Result<PathBuf> {
+        resolve_path_in_home(input, base_dir, &self.cfg.system.home_directory)
+    }
 
-        let mut child = command
-            .spawn()
-            .with_context(|| format!("failed to spawn {label}"))?;
+    /// Where a file at `path` on the machine is written: `path` itself, or
+    /// its mirror under `--root-dir` when staging.
+    pub fn target(&self, path: &Path) -> PathBuf {
+        match self.stage {
+            Some(stage) => reroot(path, stage),
+            None => path.to_path_buf(),
+        }
+    }
 
-        let stdout_handle = child.stdout.take().map(|stdout| {
-            spawn_stream_logger(self.progress.clone(), label.to_string(), "stdout", stdout)
-        });
+    /// Resolves a config path naming a file on the machine to the place it
+    /// is written, see [`StepContext::target`].
+    pub fn resolve_target(&self, input: &str, base_dir: &Path) -> Result<PathBuf> {
+        Ok(self.target(&self.resolve_path(input, base_dir)?))
+    }
 
-        let stderr_handle = child.stderr.take().map(|stderr| {
-            spawn_stream_logger(self.progress.clone(), label.to_string(), "stderr", stderr)
-        });
+    /// Whether the step called `name` is part of this run.
+    pub fn runs(&self, name: &str) -> bool {
+        self.steps.iter().any(|step| step.name() == name)
+    }
 
-        let status = child
-            .wait()
-            .with_context(|| format!("failed to wait for {label}"))?;
+    /// Gives `path`, and any root-owned directories between it and the home
+    /// directory, to the primary user when running as root.
+    pub fn take_ownership(&self, path: &Path) -> Result<()> {
+        let Some(account) = self.account.filter(|_| self.stage.is_none()) else {
+            return Ok(());
+        };
+        hand_over(path, account, &self.cfg.system.home_directory)
+    }
 
-        if let Some(handle) = stdout_handle {
-            let _ = handle.join();
-        }
-        if let Some(handle) = stderr_handle {
-            let _ = handle.join();
+    /// Runs a long command, echoing its output under the progress bar.
+    pub fn stream_command(
+        &self,
+        program: &str,
+        args: &[&str],
+        label: &str,
+    ) -> Result<CommandOutput> {
+        let stdout_prefix = style(format!("[{label}]")).dim().to_string();
+        let stderr_prefix = style(format!("[{label}:stderr]")).dim().to_string();
+        let progress = &self.progress;
+        let hidden = progress.is_hidden();
+        self.cmd.stream(program, args, &|stream, line| {
+            let prefix = match stream {
+                Stream::Stdout => &stdout_prefix,
+                Stream::Stderr => &stderr_prefix,
+            };
+            let line = format!("  {} {}", prefix, line);
+            if hidden {
+                eprintln!("{line}");
+            } else {
+                progress.println(line);
+            }
+        })
+    }
+}
+
+/// Plans a write of `contents` to `path`, comparing with normalized newlines
+/// so trailing whitespace differences do not count as drift.
+pub fn plan_write_file(path: &Path, contents: &str, mode: Option<u32>) -> Result<Option<Change>> {
+    let current = if path.exists() {
+        Some(
+            fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        )
+    } else {
+        None
+    };
+
+    if let Some(existing) = &current {
+        if normalize_newlines(existing) == normalize_newlines(contents) {
+            return Ok(match (mode, current_mode(path)) {
+                (Some(mode), Some(current)) if current != mode => Some(Change::SetMode {
+                    path: path.to_owned(),
+                    current,
+                    mode,
+                }),
+                _ => None,
+            });
         }
+    }
+
+    Ok(Some(Change::WriteFile {
+        path: path.to_owned(),
+        current,
+        contents: contents.to_owned(),
+        mode,
+    }))
+}
 
-        Ok(status)
+pub fn write_file(path: &Path, contents: &str, mode: Option<u32>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
     }
+
+    fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    if let Some(mode) = mode {
+        set_mode(path, mode)?;
+    }
+    Ok(())
 }
 
-fn spawn_stream_logger<R>(
-    progress: ProgressBar,
-    label: String,
-    stream: &'static str,
-    reader: R,
-) -> thread::JoinHandle<()>
-where
-    R: Read + Send + 'static,
-{
-    thread::spawn(move || {
-        let prefix = if stream == "stdout" {
-            style(format!("[{label}]")).dim().to_string()
-        } else {
-            style(format!("[{label}:{stream}]")).dim().to_string()
-        };
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_mode(mode);
+    fs::set_permissions(path, perms)
+        .with_context(|| format!("Failed to set permissions on {}", path.display()))
+}
 
-        let reader = BufReader::new(reader);
-        for line in reader.lines() {
-            match line {
-                Ok(line) => progress.println(format!("  {} {}", prefix, line)),
-                Err(err) => {
-                    let error_prefix = style(format!("[{label}:{stream}]")).red().to_string();
-                    progress.println(format!("  {} stream read error: {err}", error_prefix));
-                    break;
-                }
-            }
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Gives `path`, and any root-owned directories between it and `home`, to
+/// `account`.
+pub fn hand_over(path: &Path, account: &Account, home: &Path) -> Result<()> {
+    chown(path, account)?;
+    for dir in path.ancestors().skip(1) {
+        if !dir.starts_with(home) || dir == home {
+            break;
         }
-    })
+        if owner_uid(dir) == Some(0) {
+            chown(dir, account)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn chown(path: &Path, account: &Account) -> Result<()> {
+    std::os::unix::fs::chown(path, Some(account.uid), Some(account.gid))
+        .with_context(|| format!("Failed to hand {} over to {}", path.display(), account.user))
+}
+
+#[cfg(not(unix))]
+fn chown(_path: &Path, _account: &Account) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn owner_uid(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    fs::symlink_metadata(path).ok().map(|meta| meta.uid())
+}
+
+#[cfg(not(unix))]
+fn owner_uid(_path: &Path) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn current_mode(path: &Path) -> Option<u32> {
+    fs::metadata(path)
+        .ok()
+        .map(|meta| meta.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn current_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use indicatif::ProgressBar;
+
+    use super::{Step, StepContext, REGISTRY};
+    use crate::{account::Account, command::ScriptedRunner, config::Config, sudo::SudoHelper};
+
+    pub const MINIMAL_CONFIG: &str = r#"
+[system]
+home_directory = "/Users/test"
+primary_user = "test"
+
+[user]
+"#;
+
+    pub fn config(extra: &str) -> Config {
+        toml::from_str(&format!("{MINIMAL_CONFIG}\n{extra}")).expect("test config parses")
+    }
+
+    /// Runs `f` with a step context backed by `runner`.
+    pub fn with_context<T>(
+        cfg: &Config,
+        root: &Path,
+        runner: Rc<ScriptedRunner>,
+        f: impl FnOnce(&mut StepContext<'_>) -> T,
+    ) -> T {
+        staged(cfg, root, None, runner, f)
+    }
+
+    /// Like [`with_context`], with only `steps` taking part in the run.
+    pub fn with_steps<T>(
+        cfg: &Config,
+        steps: &[&'static dyn Step],
+        runner: Rc<ScriptedRunner>,
+        f: impl FnOnce(&mut StepContext<'_>) -> T,
+    ) -> T {
+        let mut sudo = SudoHelper::new(runner.clone(), false);
+        let mut ctx = StepContext {
+            cfg,
+            root: Path::new("."),
+            sudo: &mut sudo,
+            cmd: runner.as_ref(),
+            account: None,
+            stage: None,
+            steps,
+            progress: ProgressBar::hidden(),
+        };
+        f(&mut ctx)
+    }
+
+    /// Like [`with_context`], running as root on behalf of `account`.
+    pub fn as_account<T>(
+        cfg: &Config,
+        root: &Path,
+        account: &Account,
+        runner: Rc<ScriptedRunner>,
+        f: impl FnOnce(&mut StepContext<'_>) -> T,
+    ) -> T {
+        let mut sudo = SudoHelper::new(runner.clone(), false);
+        let mut ctx = StepContext {
+            cfg,
+            root,
+            sudo: &mut sudo,
+            cmd: runner.as_ref(),
+            account: Some(account),
+            stage: None,
+            steps: REGISTRY,
+            progress: ProgressBar::hidden(),
+        };
+        f(&mut ctx)
+    }
+
+    /// Like [`with_context`], writing under `stage` as with `--root-dir`.
+    pub fn staged<T>(
+        cfg: &Config,
+        root: &Path,
+        stage: Option<&Path>,
+        runner: Rc<ScriptedRunner>,
+        f: impl FnOnce(&mut StepContext<'_>) -> T,
+    ) -> T {
+        let mut sudo = SudoHelper::new(runner.clone(), false);
+        let mut ctx = StepContext {
+            cfg,
+            root,
+            sudo: &mut sudo,
+            cmd: runner.as_ref(),
+            account: None,
+            stage,
+            steps: REGISTRY,
+            progress: ProgressBar::hidden(),
+        };
+        f(&mut ctx)
+    }
 }
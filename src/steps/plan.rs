@@ -0,0 +1,207 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::{Serialize, Serializer};
+
+use crate::{
+    defaults::{DefaultsValue, Key},
+    hidutil::{Mapping, Mappings},
+    util::sha256_hex,
+};
+
+/// A single modification a step would make to the machine.
+///
+/// Steps compute these by comparing the desired config with the current
+/// state, so the same list drives both `--dry-run` output and `apply`.
+/// Serialized for reports, where file contents appear only as a size and
+/// hash.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    Timezone {
+        current: Option<String>,
+        desired: String,
+    },
+    /// One of the names `scutil` manages: `ComputerName`, `LocalHostName`
+    /// or `HostName`.
+    Hostname {
+        name: String,
+        current: Option<String>,
+        desired: String,
+    },
+    TouchIdSudo {
+        path: PathBuf,
+        /// Existing `sudo_local`, if any.
+        #[serde(serialize_with = "summarize_current")]
+        current: Option<String>,
+        #[serde(serialize_with = "summarize")]
+        contents: String,
+    },
+    Defaults {
+        domain: String,
+        key: String,
+        current_host: bool,
+        current: Option<DefaultsValue>,
+        desired: DefaultsValue,
+    },
+    /// Replace the `hidutil` modifier remapping.
+    KeyRemap {
+        current: Vec<Mapping>,
+        desired: Vec<Mapping>,
+    },
+    /// Restart an app so it picks up changed preferences.
+    RestartApp {
+        app: String,
+    },
+    BrewBundle {
+        brews: Vec<String>,
+        casks: Vec<String>,
+    },
+    Backup {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    CopyFile {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    WriteFile {
+        path: PathBuf,
+        #[serde(serialize_with = "summarize_current")]
+        current: Option<String>,
+        #[serde(serialize_with = "summarize")]
+        contents: String,
+        mode: Option<u32>,
+    },
+    SetMode {
+        path: PathBuf,
+        current: u32,
+        mode: u32,
+    },
+    GitConfig {
+        /// Config file to edit instead of `--global`, when staging.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file: Option<PathBuf>,
+        key: String,
+        current: Option<String>,
+        desired: String,
+    },
+}
+
+/// What a report says about file contents, which may hold secrets.
+#[derive(Serialize)]
+struct Contents {
+    bytes: usize,
+    sha256: String,
+}
+
+impl Contents {
+    fn of(text: &str) -> Self {
+        Contents {
+            bytes: text.len(),
+            sha256: sha256_hex(text.as_bytes()),
+        }
+    }
+}
+
+fn summarize<S: Serializer>(text: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    Contents::of(text).serialize(serializer)
+}
+
+fn summarize_current<S: Serializer>(
+    text: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    text.as_deref().map(Contents::of).serialize(serializer)
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Timezone { current, desired } => match current {
+                Some(current) => write!(f, "set timezone {current} -> {desired}"),
+                None => write!(f, "set timezone to {desired}"),
+            },
+            Change::Hostname {
+                name,
+                current,
+                desired,
+            } => match current {
+                Some(current) => write!(f, "set {name} {current} -> {desired}"),
+                None => write!(f, "set {name} to {desired}"),
+            },
+            Change::TouchIdSudo { path, .. } => {
+                write!(f, "enable pam_tid.so in {}", path.display())
+            }
+            Change::Defaults {
+                domain,
+                key,
+                current_host,
+                current,
+                desired,
+            } => {
+                let key = Key {
+                    domain,
+                    key,
+                    current_host: *current_host,
+                };
+                write!(f, "defaults write {key} -{} {desired}", desired.type_name())?;
+                match current {
+                    Some(current) => write!(f, " (currently {current})"),
+                    None => write!(f, " (currently unset)"),
+                }
+            }
+            Change::KeyRemap { current, desired } => write!(
+                f,
+                "remap keys: {} (currently {})",
+                Mappings(desired),
+                Mappings(current)
+            ),
+            Change::RestartApp { app } => write!(f, "restart {app}"),
+            Change::BrewBundle { brews, casks } => {
+                write!(f, "brew bundle")?;
+                if !brews.is_empty() {
+                    write!(f, " brews: {}", brews.join(", "))?;
+                }
+                if !casks.is_empty() {
+                    if !brews.is_empty() {
+                        write!(f, ";")?;
+                    }
+                    write!(f, " casks: {}", casks.join(", "))?;
+                }
+                Ok(())
+            }
+            Change::Backup { path, backup } => {
+                write!(f, "back up {} -> {}", path.display(), backup.display())
+            }
+            Change::CopyFile { source, target } => {
+                write!(f, "copy {} -> {}", source.display(), target.display())
+            }
+            Change::WriteFile { path, current, .. } => {
+                if current.is_some() {
+                    write!(f, "rewrite {}", path.display())
+                } else {
+                    write!(f, "create {}", path.display())
+                }
+            }
+            Change::SetMode { path, mode, .. } => {
+                write!(f, "chmod {mode:o} {}", path.display())
+            }
+            Change::GitConfig {
+                file,
+                key,
+                current,
+                desired,
+            } => {
+                match file {
+                    Some(file) => write!(f, "git config --file {} {key}: ", file.display())?,
+                    None => write!(f, "git config --global {key}: ")?,
+                }
+                match current {
+                    Some(current) => write!(f, "{current} -> {desired}"),
+                    None => write!(f, "unset -> {desired}"),
+                }
+            }
+        }
+    }
+}
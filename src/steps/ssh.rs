@@ -1,61 +1,134 @@
 use std::fs;
-#[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
 
 use anyhow::{Context, Result};
 use console::Emoji;
 use tracing::info;
 
-use super::StepContext;
-use crate::util::{normalize_newlines, resolve_path, write_if_changed};
+use super::{plan_write_file, set_mode, write_file, Change, Step, StepContext};
+use crate::{config::Config, util::normalize_newlines};
 
 const KEY: Emoji<'_, '_> = Emoji("🗝", "ssh");
 
-pub fn run(ctx: &mut StepContext<'_>) -> Result<()> {
+pub struct SshStep;
+
+impl Step for SshStep {
+    fn name(&self) -> &'static str {
+        "ssh"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "SSH"
+    }
+
+    fn is_enabled(&self, cfg: &Config) -> bool {
+        cfg.user.ssh.is_some()
+    }
+
+    fn describe(&self, _cfg: &Config) -> String {
+        "write ~/.ssh/config".to_owned()
+    }
+
+    fn plan(&self, ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+        plan(ctx)
+    }
+
+    fn apply(&self, ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+        apply(ctx, change)
+    }
+}
+
+pub fn plan(ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
     let Some(ssh_cfg) = ctx.cfg.user.ssh.as_ref() else {
         ctx.status("No SSH config provided, skipping");
-        return Ok(());
+        return Ok(Vec::new());
     };
 
+    let ssh_dir = ctx.resolve_target("~/.ssh", ctx.root)?;
+    let config_path = ssh_dir.join("config");
+    let content = normalize_newlines(&ssh_cfg.config);
+
+    Ok(plan_write_file(&config_path, &content, Some(0o600))?
+        .into_iter()
+        .collect())
+}
+
+pub fn apply(ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
     ctx.status(format!("{KEY} syncing ~/.ssh/config"));
 
-    let ssh_dir = resolve_path("~/.ssh", ctx.root)?;
+    let ssh_dir = ctx.resolve_target("~/.ssh", ctx.root)?;
     fs::create_dir_all(&ssh_dir).context("creating ~/.ssh")?;
-    #[cfg(unix)]
-    {
-        let mut perms = fs::metadata(&ssh_dir)?.permissions();
-        perms.set_mode(0o700);
-        fs::set_permissions(&ssh_dir, perms)?;
+    set_mode(&ssh_dir, 0o700)?;
+    ctx.take_ownership(&ssh_dir)?;
+
+    match change {
+        Change::WriteFile {
+            path,
+            contents,
+            mode,
+            ..
+        } => {
+            write_file(path, contents, *mode)?;
+            ctx.take_ownership(path)?;
+            ctx.info(format!("updated {}", path.display()));
+            info!("updated {}", path.display());
+        }
+        Change::SetMode { path, mode, .. } => {
+            set_mode(path, *mode)?;
+            ctx.info(format!("fixed permissions on {}", path.display()));
+        }
+        other => unreachable!("ssh step cannot apply {other}"),
     }
 
-    let config_path = ssh_dir.join("config");
-    let content = normalize_newlines(&ssh_cfg.config);
+    Ok(())
+}
 
-    ctx.info(format!(
-        "updating \n------\n{content}\n------\n{}",
-        config_path.display()
-    ));
-    info!(
-        "updating \n------\n{content}\n------\n{}",
-        config_path.display()
-    );
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
 
-    let changed = write_if_changed(&config_path, &content)?;
+    use super::*;
+    use crate::{command::ScriptedRunner, steps::testing};
 
-    #[cfg(unix)]
-    {
-        let mut perms = fs::metadata(&config_path)?.permissions();
-        perms.set_mode(0o600);
-        fs::set_permissions(&config_path, perms)?;
-    }
+    #[test]
+    fn staged_run_writes_under_root_dir() {
+        let stage = tempfile::tempdir().unwrap();
+        let cfg = testing::config(
+            r#"
+[user.ssh]
+config = "Host *\n  AddKeysToAgent yes"
+"#,
+        );
 
-    if changed {
-        ctx.info(format!("updated {}", config_path.display()));
-        info!("updated {}", config_path.display());
-    } else {
-        ctx.info(format!("{} already up to date", config_path.display()));
-        info!("{} already up to date", config_path.display());
-    }
+        let run = |stage: &std::path::Path| {
+            testing::staged(
+                &cfg,
+                stage,
+                Some(stage),
+                Rc::new(ScriptedRunner::new()),
+                |ctx| {
+                    let changes = plan(ctx)?;
+                    for change in &changes {
+                        apply(ctx, change)?;
+                    }
+                    Ok::<_, anyhow::Error>(changes.len())
+                },
+            )
+            .unwrap()
+        };
 
-    Ok(())
+        assert_eq!(run(stage.path()), 1);
+        let config = stage.path().join("Users/test/.ssh/config");
+        assert_eq!(
+            fs::read_to_string(&config).unwrap(),
+            "Host *\n  AddKeysToAgent yes\n"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode(&config) & 0o777, 0o600);
+            assert_eq!(mode(config.parent().unwrap()) & 0o777, 0o700);
+        }
+        assert_eq!(run(stage.path()), 0, "second run is a no-op");
+    }
 }
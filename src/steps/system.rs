@@ -1,148 +1,959 @@
 use std::fs;
 use std::io::{ErrorKind, Write};
 use std::path::Path;
-use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use console::Emoji;
 use tempfile::NamedTempFile;
 use tracing::info;
 
-use super::StepContext;
-use crate::config::SystemConfig;
+use super::{dock, write_file, Change, Step, StepContext};
+use crate::{
+    capture,
+    config::{Config, SystemConfig},
+    defaults::{self, DefaultsValue, Key},
+    hidutil::{self, Mapping, Mappings, LAUNCH_AGENT_LABEL},
+    sudo::SudoHelper,
+    util::{file_url, resolve_path_in_home},
+};
 
 const SPARKLES: Emoji<'_, '_> = Emoji("✨", "*");
 
-pub fn run(ctx: &mut StepContext<'_>) -> Result<()> {
+const SCUTIL: &str = "/usr/sbin/scutil";
+
+const LOCALTIME: &str = "/etc/localtime";
+
+const PAM_SUDO: &str = "/etc/pam.d/sudo";
+const PAM_SUDO_LOCAL: &str = "/etc/pam.d/sudo_local";
+
+pub const TRACKPAD_DOMAINS: [&str; 2] = [
+    "com.apple.AppleMultitouchTrackpad",
+    "com.apple.driver.AppleBluetoothMultitouch.trackpad",
+];
+
+pub struct SystemStep;
+
+impl Step for SystemStep {
+    fn name(&self) -> &'static str {
+        "system"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "System"
+    }
+
+    fn is_enabled(&self, _cfg: &Config) -> bool {
+        true
+    }
+
+    fn describe(&self, cfg: &Config) -> String {
+        let system = &cfg.system;
+        let mut parts = Vec::new();
+        if let Some(tz) = &system.timezone {
+            parts.push(format!("timezone {tz}"));
+        }
+        if let Some(hostname) = &system.hostname {
+            parts.push(format!("hostname {hostname}"));
+        } else if !desired_hostnames(system).is_empty() {
+            parts.push("hostnames".to_owned());
+        }
+        if system.touch_id_sudo {
+            parts.push("Touch ID for sudo".to_owned());
+        }
+        if has_trackpad_settings(system) {
+            parts.push("trackpad defaults".to_owned());
+        }
+        if has_finder_settings(system) {
+            parts.push("Finder prefs".to_owned());
+        }
+        if has_screenshot_settings(system) {
+            parts.push("screenshot prefs".to_owned());
+        }
+        if has_keyboard_settings(system) {
+            parts.push("keyboard prefs".to_owned());
+        }
+        if let Some(remap) = &system.keyboard.remap {
+            parts.push(format!("{} key remaps", remap.len()));
+        }
+        if !system.defaults.is_empty() {
+            parts.push(format!("{} defaults", system.defaults.len()));
+        }
+        if parts.is_empty() {
+            "nothing configured".to_owned()
+        } else {
+            parts.join(", ")
+        }
+    }
+
+    fn plan(&self, ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
+        plan(ctx)
+    }
+
+    fn apply(&self, ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+        apply(ctx, change)
+    }
+}
+
+pub fn plan(ctx: &mut StepContext<'_>) -> Result<Vec<Change>> {
     let system = &ctx.cfg.system;
+    let mut changes = Vec::new();
 
     if !has_system_tasks(system) {
         ctx.status("No system settings configured, skipping");
-        return Ok(());
+        return Ok(changes);
+    }
+
+    // Only files can be staged; the rest lives in system databases.
+    let staged = ctx.stage.is_some();
+    let remap = system.keyboard.remap.as_ref();
+    let hostnames = desired_hostnames(system);
+    let unstageable = system.timezone.is_some()
+        || !hostnames.is_empty()
+        || has_defaults(system)
+        || remap.is_some();
+    if staged && unstageable {
+        ctx.info("--root-dir: skipping timezone, hostnames, defaults and live key remapping");
     }
 
-    if let Some(tz) = &system.timezone {
-        ctx.info(format!("{SPARKLES} Setting timezone to {tz}"));
-        ensure_timezone(ctx, tz)?;
+    if let Some(tz) = system.timezone.as_ref().filter(|_| !staged) {
+        changes.extend(plan_timezone(Path::new(LOCALTIME), tz));
+    }
+
+    if !staged {
+        for (name, desired) in hostnames {
+            changes.extend(plan_hostname(ctx, name, desired)?);
+        }
     }
 
     if system.touch_id_sudo {
-        ctx.info(format!("{SPARKLES} Enabling Touch ID for sudo"));
-        enable_touch_id(ctx)?;
-    }
-
-    if let Some(clicking) = system.trackpad.clicking {
-        ctx.info(format!("{SPARKLES} Trackpad clicking -> {}", clicking));
-        ensure_trackpad_bool("com.apple.AppleMultitouchTrackpad", "Clicking", clicking)?;
-        ensure_trackpad_bool(
-            "com.apple.driver.AppleBluetoothMultitouch.trackpad",
-            "Clicking",
-            clicking,
-        )?;
-    }
-
-    if let Some(drag) = system.trackpad.three_finger_drag {
-        ctx.info(format!("{SPARKLES} Trackpad three finger drag -> {}", drag));
-        ensure_trackpad_bool(
-            "com.apple.AppleMultitouchTrackpad",
-            "TrackpadThreeFingerDrag",
-            drag,
-        )?;
-        ensure_trackpad_bool(
-            "com.apple.driver.AppleBluetoothMultitouch.trackpad",
-            "TrackpadThreeFingerDrag",
-            drag,
-        )?;
+        changes.extend(plan_touch_id(ctx)?);
     }
 
-    Ok(())
+    if let Some(remap) = remap {
+        changes.extend(plan_remap(ctx, &hidutil::mappings(remap))?);
+    }
+
+    if staged {
+        return Ok(changes);
+    }
+
+    let mut reader = defaults::Reader::new(ctx.cmd);
+    let mut restarts = Vec::new();
+    for (key, desired) in desired_defaults(system)? {
+        // With `[system.dock]` set and the dock step running, it owns its
+        // domain so the Dock restarts only once.
+        if system.dock.is_some() && ctx.runs("dock") && key.domain == dock::DOMAIN {
+            continue;
+        }
+        let Some(change) = plan_defaults(&mut reader, key, desired)? else {
+            continue;
+        };
+        if let Some(app) = defaults::owning_app(key.domain, key.key) {
+            if !restarts.contains(&app) {
+                restarts.push(app);
+            }
+        }
+        changes.push(change);
+    }
+    // Each app is restarted once, after every preference it reads is written.
+    changes.extend(restarts.into_iter().map(|app| Change::RestartApp {
+        app: app.to_owned(),
+    }));
+
+    Ok(changes)
+}
+
+pub fn apply(ctx: &mut StepContext<'_>, change: &Change) -> Result<()> {
+    match change {
+        Change::Timezone { desired, .. } => {
+            ctx.info(format!("{SPARKLES} Setting timezone to {desired}"));
+            set_timezone(ctx.sudo, desired)
+        }
+        Change::Hostname { name, desired, .. } => {
+            ctx.info(format!("{SPARKLES} Setting {name} to {desired}"));
+            set_hostname(ctx.sudo, name, desired)
+        }
+        Change::TouchIdSudo { path, contents, .. } => {
+            ctx.info(format!("{SPARKLES} Enabling Touch ID for sudo"));
+            write_privileged_file(ctx.sudo, path, contents)
+        }
+        Change::WriteFile {
+            path,
+            contents,
+            mode,
+            ..
+        } => {
+            ctx.info(format!("{SPARKLES} Writing {}", path.display()));
+            write_file(path, contents, *mode)?;
+            ctx.take_ownership(path)
+        }
+        Change::KeyRemap { desired, .. } => {
+            ctx.info(format!("{SPARKLES} Remapping keys: {}", Mappings(desired)));
+            hidutil::set(ctx.cmd, desired)
+        }
+        Change::Defaults {
+            domain,
+            key,
+            current_host,
+            desired,
+            ..
+        } => {
+            let key = Key {
+                domain,
+                key,
+                current_host: *current_host,
+            };
+            ctx.info(format!("{SPARKLES} {key} -> {desired}"));
+            defaults::write(ctx.cmd, key, desired)
+        }
+        Change::RestartApp { app } => {
+            ctx.info(format!("{SPARKLES} Restarting {app}"));
+            defaults::restart(ctx.cmd, app)
+        }
+        other => unreachable!("system step cannot apply {other}"),
+    }
 }
 
 fn has_system_tasks(system: &SystemConfig) -> bool {
     system.timezone.is_some()
+        || !desired_hostnames(system).is_empty()
         || system.touch_id_sudo
-        || system.trackpad.clicking.is_some()
-        || system.trackpad.three_finger_drag.is_some()
+        || has_defaults(system)
+        || system.keyboard.remap.is_some()
+}
+
+fn has_trackpad_settings(system: &SystemConfig) -> bool {
+    system.trackpad.clicking.is_some() || system.trackpad.three_finger_drag.is_some()
+}
+
+fn has_finder_settings(system: &SystemConfig) -> bool {
+    let finder = &system.finder;
+    finder.show_extensions.is_some()
+        || finder.show_hidden_files.is_some()
+        || finder.show_path_bar.is_some()
+        || finder.show_status_bar.is_some()
+        || finder.default_view.is_some()
+        || finder.new_window_target.is_some()
+        || finder.disable_network_ds_store.is_some()
+}
+
+fn has_screenshot_settings(system: &SystemConfig) -> bool {
+    let screenshots = &system.screenshots;
+    screenshots.location.is_some()
+        || screenshots.format.is_some()
+        || screenshots.disable_shadow.is_some()
 }
 
-fn ensure_timezone(ctx: &mut StepContext<'_>, target: &str) -> Result<()> {
-    let current = ctx
-        .sudo
-        .run_with_output("/usr/sbin/systemsetup", &["-gettimezone"])
-        .unwrap_or_default();
+fn has_keyboard_settings(system: &SystemConfig) -> bool {
+    let keyboard = &system.keyboard;
+    keyboard.key_repeat.is_some()
+        || keyboard.initial_key_repeat.is_some()
+        || keyboard.press_and_hold.is_some()
+        || keyboard.fn_as_standard.is_some()
+}
+
+fn has_defaults(system: &SystemConfig) -> bool {
+    has_trackpad_settings(system)
+        || has_finder_settings(system)
+        || has_screenshot_settings(system)
+        || has_keyboard_settings(system)
+        || !system.defaults.is_empty()
+}
 
-    if current.trim().ends_with(target) {
+/// Reads the zone from the `/etc/localtime` link, which needs no sudo, so
+/// `status` can check it non-interactively.
+fn plan_timezone(localtime: &Path, target: &str) -> Option<Change> {
+    let current = capture::timezone(localtime);
+    if current.as_deref() == Some(target) {
         info!("timezone already {target}");
-        return Ok(());
+        return None;
     }
 
-    ctx.sudo
-        .run("/usr/sbin/systemsetup", &["-settimezone", target])
+    Some(Change::Timezone {
+        current,
+        desired: target.to_owned(),
+    })
+}
+
+pub fn set_timezone(sudo: &mut SudoHelper, target: &str) -> Result<()> {
+    sudo.run("/usr/sbin/systemsetup", &["-settimezone", target])
         .with_context(|| format!("unable to set timezone to {target}"))
 }
 
-fn enable_touch_id(ctx: &mut StepContext<'_>) -> Result<()> {
-    let pam_path = Path::new("/etc/pam.d/sudo_local");
-    let contents = match fs::read_to_string(pam_path) {
-        Ok(contents) => contents,
-        Err(err) if err.kind() == ErrorKind::NotFound => {
-            fs::read_to_string("/etc/pam.d/sudo").with_context(|| "reading /etc/pam.d/sudo")?
-        }
-        Err(err) => {
-            return Err(err).with_context(|| "reading /etc/pam.d/sudo_local");
+/// The `scutil` names the config sets, each falling back to `hostname`.
+pub fn desired_hostnames(system: &SystemConfig) -> Vec<(&'static str, &str)> {
+    let hostname = system.hostname.as_deref();
+    [
+        ("ComputerName", system.computer_name.as_deref().or(hostname)),
+        (
+            "LocalHostName",
+            system.local_host_name.as_deref().or(hostname),
+        ),
+        ("HostName", system.host_name.as_deref().or(hostname)),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value?)))
+    .collect()
+}
+
+/// Reading needs no privileges, so a machine that already matches never
+/// prompts for sudo.
+fn plan_hostname(ctx: &StepContext<'_>, name: &str, desired: &str) -> Result<Option<Change>> {
+    let output = ctx
+        .cmd
+        .output(SCUTIL, &["--get", name])
+        .with_context(|| format!("reading {name}"))?;
+    // An unset HostName is reported as `HostName: not set` with exit code 1.
+    let current = if output.success() {
+        Some(output.stdout.trim().to_owned())
+    } else if output.stdout.contains("not set") || output.stderr.contains("not set") {
+        None
+    } else {
+        bail!("scutil --get {name} failed: {}", output.stderr.trim());
+    };
+
+    if current.as_deref() == Some(desired) {
+        info!("{name} already {desired}");
+        return Ok(None);
+    }
+    Ok(Some(Change::Hostname {
+        name: name.to_owned(),
+        current,
+        desired: desired.to_owned(),
+    }))
+}
+
+pub fn set_hostname(sudo: &mut SudoHelper, name: &str, value: &str) -> Result<()> {
+    sudo.run(SCUTIL, &["--set", name, value])
+        .with_context(|| format!("unable to set {name} to {value}"))
+}
+
+fn plan_touch_id(ctx: &StepContext<'_>) -> Result<Option<Change>> {
+    let pam_path = ctx.target(Path::new(PAM_SUDO_LOCAL));
+    let current = read_if_exists(&pam_path)?;
+    let contents = match &current {
+        Some(contents) => contents.clone(),
+        None => {
+            // A staged tree may not carry its own copy of the sudo config.
+            let staged = ctx.target(Path::new(PAM_SUDO));
+            match read_if_exists(&staged)? {
+                Some(contents) => contents,
+                None => {
+                    fs::read_to_string(PAM_SUDO).with_context(|| format!("reading {PAM_SUDO}"))?
+                }
+            }
         }
     };
     if contents.contains("pam_tid.so") {
-        return Ok(());
+        return Ok(None);
+    }
+
+    let contents = format!("auth       sufficient     pam_tid.so\n{contents}");
+    if ctx.stage.is_some() {
+        return Ok(Some(Change::WriteFile {
+            path: pam_path,
+            current,
+            contents,
+            mode: Some(0o644),
+        }));
     }
+    Ok(Some(Change::TouchIdSudo {
+        path: pam_path,
+        current,
+        contents,
+    }))
+}
+
+/// Applies `desired` now with `hidutil`, and installs a LaunchAgent that
+/// applies it again at every login.
+fn plan_remap(ctx: &StepContext<'_>, desired: &[Mapping]) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    if ctx.stage.is_none() {
+        let current = hidutil::read(ctx.cmd)?;
+        if current != desired {
+            changes.push(Change::KeyRemap {
+                current,
+                desired: desired.to_vec(),
+            });
+        }
+    }
+
+    let agent = format!("~/Library/LaunchAgents/{LAUNCH_AGENT_LABEL}.plist");
+    let path = ctx.resolve_target(&agent, ctx.root)?;
+    let current = read_if_exists(&path)?;
+    let contents = hidutil::launch_agent(desired)?;
+    if current.as_deref() != Some(contents.as_str()) {
+        changes.push(Change::WriteFile {
+            path,
+            current,
+            contents,
+            mode: Some(0o644),
+        });
+    }
+    Ok(changes)
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("reading {}", path.display())),
+    }
+}
 
+/// Installs `contents` at a root-owned `path` with mode 644.
+pub fn write_privileged_file(sudo: &mut SudoHelper, path: &Path, contents: &str) -> Result<()> {
     let mut tmp = NamedTempFile::new().context("allocating temp file")?;
-    writeln!(tmp, "auth       sufficient     pam_tid.so")?;
     write!(tmp, "{contents}")?;
     tmp.flush()?;
 
     let tmp_path = tmp.path().to_str().context("temp path not valid utf8")?;
-    let dest = pam_path.to_str().context("pam path not utf8")?;
-    ctx.sudo
-        .run("/bin/cp", &[tmp_path, dest])
-        .context("updating /etc/pam.d/sudo_local")?;
-    ctx.sudo
-        .run("/bin/chmod", &["644", dest])
+    let dest = path.to_str().context("pam path not utf8")?;
+    sudo.run("/bin/cp", &[tmp_path, dest])
+        .with_context(|| format!("updating {dest}"))?;
+    sudo.run("/bin/chmod", &["644", dest])
         .context("fixing pam perms")?;
 
     Ok(())
 }
 
-fn ensure_trackpad_bool(domain: &str, key: &str, desired: bool) -> Result<()> {
-    let current = read_defaults_bool(domain, key);
-    if current == Some(desired) {
-        return Ok(());
+/// Every preference the config asks for: trackpad, Finder and screenshot
+/// keys, then `[[system.defaults]]`. A later
+/// entry for the same key replaces an earlier one, so profiles and includes
+/// can override a base entry.
+fn desired_defaults(system: &SystemConfig) -> Result<Vec<(Key<'_>, DefaultsValue)>> {
+    let mut all = Vec::new();
+
+    let trackpad = [
+        ("Clicking", system.trackpad.clicking),
+        ("TrackpadThreeFingerDrag", system.trackpad.three_finger_drag),
+    ];
+    for (key, value) in trackpad {
+        let Some(value) = value else { continue };
+        for domain in TRACKPAD_DOMAINS {
+            let key = Key {
+                domain,
+                key,
+                current_host: false,
+            };
+            all.push((key, DefaultsValue::Bool(value)));
+        }
     }
 
-    let flag = if desired { "TRUE" } else { "FALSE" };
-    let status = Command::new("/usr/bin/defaults")
-        .args(["write", domain, key, "-bool", flag])
-        .status()
-        .with_context(|| format!("defaults write {domain} {key}"))?;
-    if status.success() {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("defaults write {domain} {key} failed"))
+    all.extend(finder_defaults(system)?);
+    all.extend(screenshot_defaults(system)?);
+    all.extend(keyboard_defaults(system));
+
+    all.extend(defaults_entries(system)?);
+    Ok(dedup_defaults(all))
+}
+
+/// The `[[system.defaults]]` entries, in config order.
+pub fn defaults_entries(system: &SystemConfig) -> Result<Vec<(Key<'_>, DefaultsValue)>> {
+    system
+        .defaults
+        .iter()
+        .map(|entry| {
+            let key = Key {
+                domain: &entry.domain,
+                key: &entry.key,
+                current_host: entry.current_host,
+            };
+            let value = DefaultsValue::from_config(entry.kind, &entry.value)
+                .with_context(|| format!("system.defaults: {key}"))?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// Keeps the last value for each key, in the order keys were last set.
+pub fn dedup_defaults<'a>(all: Vec<(Key<'a>, DefaultsValue)>) -> Vec<(Key<'a>, DefaultsValue)> {
+    let mut desired: Vec<(Key<'a>, DefaultsValue)> = Vec::new();
+    for (key, value) in all {
+        desired.retain(|(existing, _)| *existing != key);
+        desired.push((key, value));
     }
+    desired
 }
 
-fn read_defaults_bool(domain: &str, key: &str) -> Option<bool> {
-    let output = Command::new("/usr/bin/defaults")
-        .args(["read", domain, key])
-        .output()
-        .ok()?;
-    if !output.status.success() {
-        return None;
+fn finder_defaults(system: &SystemConfig) -> Result<Vec<(Key<'static>, DefaultsValue)>> {
+    let finder = &system.finder;
+    let key = |domain, key| Key {
+        domain,
+        key,
+        current_host: false,
+    };
+    let mut desired = Vec::new();
+    let bools = [
+        (
+            "NSGlobalDomain",
+            "AppleShowAllExtensions",
+            finder.show_extensions,
+        ),
+        (
+            "com.apple.finder",
+            "AppleShowAllFiles",
+            finder.show_hidden_files,
+        ),
+        ("com.apple.finder", "ShowPathbar", finder.show_path_bar),
+        ("com.apple.finder", "ShowStatusBar", finder.show_status_bar),
+        (
+            "com.apple.desktopservices",
+            "DSDontWriteNetworkStores",
+            finder.disable_network_ds_store,
+        ),
+    ];
+    for (domain, name, value) in bools {
+        if let Some(value) = value {
+            desired.push((key(domain, name), DefaultsValue::Bool(value)));
+        }
+    }
+    if let Some(view) = finder.default_view {
+        let value = DefaultsValue::String(view.code().to_owned());
+        desired.push((key("com.apple.finder", "FXPreferredViewStyle"), value));
     }
-    let raw = String::from_utf8_lossy(&output.stdout);
-    match raw.trim() {
-        "1" | "YES" | "TRUE" | "true" => Some(true),
-        "0" | "NO" | "FALSE" | "false" => Some(false),
+    if let Some(target) = &finder.new_window_target {
+        let string = |value: &str| DefaultsValue::String(value.to_owned());
+        match new_window_code(target) {
+            Some(code) => {
+                desired.push((key("com.apple.finder", "NewWindowTarget"), string(code)));
+            }
+            None => {
+                let home = &system.home_directory;
+                let path = resolve_path_in_home(target, home, home)
+                    .with_context(|| format!("system.finder.new_window_target: {target}"))?;
+                desired.push((key("com.apple.finder", "NewWindowTarget"), string("PfLo")));
+                desired.push((
+                    key("com.apple.finder", "NewWindowTargetPath"),
+                    string(&file_url(&path)),
+                ));
+            }
+        }
+    }
+    Ok(desired)
+}
+
+/// The `NewWindowTarget` code for a named location, or `None` for a path.
+pub fn new_window_code(target: &str) -> Option<&'static str> {
+    match target {
+        "home" => Some("PfHm"),
+        "desktop" => Some("PfDe"),
+        "documents" => Some("PfDo"),
+        "computer" => Some("PfCm"),
         _ => None,
     }
 }
+
+fn screenshot_defaults(system: &SystemConfig) -> Result<Vec<(Key<'static>, DefaultsValue)>> {
+    let screenshots = &system.screenshots;
+    let key = |key| Key {
+        domain: "com.apple.screencapture",
+        key,
+        current_host: false,
+    };
+    let mut desired = Vec::new();
+    if let Some(location) = &screenshots.location {
+        let home = &system.home_directory;
+        let path = resolve_path_in_home(location, home, home)
+            .with_context(|| format!("system.screenshots.location: {location}"))?;
+        let path = path
+            .to_str()
+            .context("screenshot location is not valid UTF-8")?;
+        desired.push((key("location"), DefaultsValue::String(path.to_owned())));
+    }
+    if let Some(format) = screenshots.format {
+        let value = DefaultsValue::String(format.as_str().to_owned());
+        desired.push((key("type"), value));
+    }
+    if let Some(disable) = screenshots.disable_shadow {
+        desired.push((key("disable-shadow"), DefaultsValue::Bool(disable)));
+    }
+    Ok(desired)
+}
+
+fn keyboard_defaults(system: &SystemConfig) -> Vec<(Key<'static>, DefaultsValue)> {
+    let keyboard = &system.keyboard;
+    let key = |key| Key {
+        domain: "NSGlobalDomain",
+        key,
+        current_host: false,
+    };
+    let ints = [
+        ("KeyRepeat", keyboard.key_repeat),
+        ("InitialKeyRepeat", keyboard.initial_key_repeat),
+    ];
+    let bools = [
+        ("ApplePressAndHoldEnabled", keyboard.press_and_hold),
+        ("com.apple.keyboard.fnState", keyboard.fn_as_standard),
+    ];
+    ints.into_iter()
+        .filter_map(|(name, value)| Some((key(name), DefaultsValue::Int(value?))))
+        .chain(
+            bools
+                .into_iter()
+                .filter_map(|(name, value)| Some((key(name), DefaultsValue::Bool(value?)))),
+        )
+        .collect()
+}
+
+pub fn plan_defaults(
+    reader: &mut defaults::Reader<'_>,
+    key: Key<'_>,
+    desired: DefaultsValue,
+) -> Result<Option<Change>> {
+    let current = reader.read(key)?;
+    if current.as_ref() == Some(&desired) {
+        info!("defaults {key} already {desired}");
+        return Ok(None);
+    }
+
+    Ok(Some(Change::Defaults {
+        domain: key.domain.to_owned(),
+        key: key.key.to_owned(),
+        current_host: key.current_host,
+        current,
+        desired,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{command::ScriptedRunner, steps::testing};
+
+    const EXPORT_HEAD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+"#;
+
+    fn export(body: &str) -> String {
+        format!("{EXPORT_HEAD}{body}\n</plist>\n")
+    }
+
+    fn plan_with(cfg: &Config, runner: Rc<ScriptedRunner>) -> Vec<Change> {
+        testing::with_context(cfg, Path::new("."), runner, plan).unwrap()
+    }
+
+    #[test]
+    fn trackpad_bools_are_compared_by_type() {
+        let mut cfg = testing::config("");
+        cfg.system.trackpad.clicking = Some(true);
+        let runner = Rc::new(ScriptedRunner::new());
+        runner
+            .on(
+                "/usr/bin/defaults export com.apple.AppleMultitouchTrackpad -",
+                0,
+                &export("<dict><key>Clicking</key><true/></dict>"),
+            )
+            .on(
+                "/usr/bin/defaults export com.apple.driver.AppleBluetoothMultitouch.trackpad -",
+                0,
+                // Written as an int by something else: not the bool we want.
+                &export("<dict><key>Clicking</key><integer>1</integer></dict>"),
+            );
+
+        let changes = plan_with(&cfg, runner);
+
+        assert_eq!(
+            changes,
+            vec![Change::Defaults {
+                domain: "com.apple.driver.AppleBluetoothMultitouch.trackpad".to_owned(),
+                key: "Clicking".to_owned(),
+                current_host: false,
+                current: Some(DefaultsValue::Int(1)),
+                desired: DefaultsValue::Bool(true),
+            }]
+        );
+    }
+
+    #[test]
+    fn later_defaults_entries_replace_earlier_ones() {
+        let cfg = testing::config(
+            r#"
+[[system.defaults]]
+domain = "com.apple.dock"
+key = "tilesize"
+type = "int"
+value = 36
+
+[[system.defaults]]
+domain = "NSGlobalDomain"
+key = "AppleShowAllExtensions"
+current_host = true
+type = "bool"
+value = true
+
+[[system.defaults]]
+domain = "com.apple.dock"
+key = "tilesize"
+type = "int"
+value = 48
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner
+            .on(
+                "/usr/bin/defaults export com.apple.dock -",
+                0,
+                &export("<dict><key>tilesize</key><integer>36</integer></dict>"),
+            )
+            .on(
+                "/usr/bin/defaults -currentHost export NSGlobalDomain -",
+                0,
+                &export("<dict/>"),
+            )
+            .on(
+                "/usr/bin/defaults write com.apple.dock tilesize -int 48",
+                0,
+                "",
+            );
+
+        let changes = plan_with(&cfg, runner.clone());
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "defaults write -currentHost NSGlobalDomain AppleShowAllExtensions -bool true (currently unset)",
+                "defaults write com.apple.dock tilesize -int 48 (currently 36)",
+                "restart Finder",
+                "restart Dock",
+            ]
+        );
+
+        testing::with_context(&cfg, Path::new("."), runner.clone(), |ctx| {
+            apply(ctx, &changes[1])
+        })
+        .unwrap();
+        assert_eq!(
+            runner.calls().last().map(String::as_str),
+            Some("/usr/bin/defaults write com.apple.dock tilesize -int 48")
+        );
+    }
+
+    #[test]
+    fn finder_and_screenshot_apps_restart_once_after_their_keys() {
+        let cfg = testing::config(
+            r#"
+[system.finder]
+show_hidden_files = true
+show_path_bar = true
+default_view = "column"
+new_window_target = "~/Code"
+
+[system.screenshots]
+location = "~/Pictures/Screen Shots"
+format = "png"
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner
+            .on(
+                "/usr/bin/defaults export com.apple.finder -",
+                0,
+                &export("<dict><key>ShowPathbar</key><true/></dict>"),
+            )
+            .on(
+                "/usr/bin/defaults export com.apple.screencapture -",
+                0,
+                &export("<dict><key>type</key><string>png</string></dict>"),
+            )
+            .on("/usr/bin/killall Finder", 0, "");
+
+        let changes = plan_with(&cfg, runner.clone());
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "defaults write com.apple.finder AppleShowAllFiles -bool true (currently unset)",
+                "defaults write com.apple.finder FXPreferredViewStyle -string \"clmv\" (currently unset)",
+                "defaults write com.apple.finder NewWindowTarget -string \"PfLo\" (currently unset)",
+                "defaults write com.apple.finder NewWindowTargetPath -string \"file:///Users/test/Code/\" (currently unset)",
+                "defaults write com.apple.screencapture location -string \"/Users/test/Pictures/Screen Shots\" (currently unset)",
+                "restart Finder",
+                "restart SystemUIServer",
+            ]
+        );
+
+        testing::with_context(&cfg, Path::new("."), runner.clone(), |ctx| {
+            apply(ctx, &changes[5])
+        })
+        .unwrap();
+        assert_eq!(
+            runner.calls().last().map(String::as_str),
+            Some("/usr/bin/killall Finder")
+        );
+    }
+
+    #[test]
+    fn touch_id_is_staged_as_a_plain_file() {
+        let stage = tempfile::tempdir().unwrap();
+        let pam = stage.path().join("etc/pam.d");
+        fs::create_dir_all(&pam).unwrap();
+        fs::write(
+            pam.join("sudo"),
+            "auth       required       pam_opendirectory.so\n",
+        )
+        .unwrap();
+        let mut cfg = testing::config("");
+        cfg.system.timezone = Some("Europe/Paris".to_owned());
+        cfg.system.touch_id_sudo = true;
+        let runner = Rc::new(ScriptedRunner::new());
+
+        let changes = testing::staged(
+            &cfg,
+            stage.path(),
+            Some(stage.path()),
+            runner.clone(),
+            |ctx| {
+                let changes = plan(ctx)?;
+                for change in &changes {
+                    apply(ctx, change)?;
+                }
+                Ok::<_, anyhow::Error>(changes)
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(
+            &changes[..],
+            [Change::WriteFile { current: None, .. }]
+        ));
+        assert_eq!(
+            fs::read_to_string(pam.join("sudo_local")).unwrap(),
+            "auth       sufficient     pam_tid.so\n\
+             auth       required       pam_opendirectory.so\n"
+        );
+        assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+    }
+
+    #[test]
+    fn key_remap_is_applied_now_and_at_login() {
+        let stage = tempfile::tempdir().unwrap();
+        let cfg = testing::config(
+            r#"
+[system.keyboard]
+key_repeat = 2
+remap = { caps_lock = "control" }
+"#,
+        );
+        let runner = Rc::new(ScriptedRunner::new());
+        runner
+            .on(
+                "/usr/bin/defaults export NSGlobalDomain -",
+                0,
+                &export("<dict><key>KeyRepeat</key><integer>2</integer></dict>"),
+            )
+            .on(
+                "/usr/bin/hidutil property --get UserKeyMapping",
+                0,
+                "(null)\n",
+            );
+
+        let changes = plan_with(&cfg, runner.clone());
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines[0],
+            "remap keys: caps_lock -> control (currently none)"
+        );
+        assert_eq!(changes.len(), 2);
+        let Change::WriteFile { path, .. } = &changes[1] else {
+            panic!("expected a LaunchAgent, got {}", changes[1]);
+        };
+        assert_eq!(
+            path,
+            Path::new(
+                "/Users/test/Library/LaunchAgents/com.github.jjyr.setup-my-mac.keyremap.plist"
+            )
+        );
+
+        // Staged, only the LaunchAgent is written and hidutil is left alone.
+        let runner = Rc::new(ScriptedRunner::new());
+        testing::staged(
+            &cfg,
+            stage.path(),
+            Some(stage.path()),
+            runner.clone(),
+            |ctx| {
+                for change in plan(ctx)? {
+                    apply(ctx, &change)?;
+                }
+                Ok::<_, anyhow::Error>(())
+            },
+        )
+        .unwrap();
+        let agent = stage
+            .path()
+            .join("Users/test/Library/LaunchAgents/com.github.jjyr.setup-my-mac.keyremap.plist");
+        assert!(fs::read_to_string(agent)
+            .unwrap()
+            .contains("/usr/bin/hidutil"));
+        assert!(runner.calls().is_empty(), "{:?}", runner.calls());
+    }
+
+    #[test]
+    fn timezone_is_read_from_the_localtime_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let localtime = dir.path().join("localtime");
+        std::os::unix::fs::symlink("/var/db/timezone/zoneinfo/Europe/Paris", &localtime).unwrap();
+
+        assert_eq!(plan_timezone(&localtime, "Europe/Paris"), None);
+        assert_eq!(
+            plan_timezone(&localtime, "Asia/Tokyo"),
+            Some(Change::Timezone {
+                current: Some("Europe/Paris".to_owned()),
+                desired: "Asia/Tokyo".to_owned(),
+            })
+        );
+        assert_eq!(
+            plan_timezone(&dir.path().join("missing"), "Asia/Tokyo"),
+            Some(Change::Timezone {
+                current: None,
+                desired: "Asia/Tokyo".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn hostnames_fall_back_to_hostname_and_skip_matches() {
+        let mut cfg = testing::config("");
+        cfg.system.hostname = Some("jane-mbp".to_owned());
+        cfg.system.computer_name = Some("Jane's MacBook Pro".to_owned());
+        let runner = Rc::new(ScriptedRunner::new());
+        runner
+            .on(
+                "/usr/sbin/scutil --get ComputerName",
+                0,
+                "Jane's MacBook Pro\n",
+            )
+            .on(
+                "/usr/sbin/scutil --get LocalHostName",
+                0,
+                "Janes-MacBook-Pro\n",
+            )
+            .on("/usr/sbin/scutil --get HostName", 1, "HostName: not set\n")
+            .on("sudo -n true", 0, "")
+            .on("sudo /usr/sbin/scutil --set HostName jane-mbp", 0, "");
+
+        let changes = plan_with(&cfg, runner.clone());
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "set LocalHostName Janes-MacBook-Pro -> jane-mbp",
+                "set HostName to jane-mbp",
+            ]
+        );
+        assert!(!runner.calls().iter().any(|call| call.starts_with("sudo")));
+
+        testing::with_context(&cfg, Path::new("."), runner.clone(), |ctx| {
+            apply(ctx, &changes[1])
+        })
+        .unwrap();
+        assert_eq!(
+            runner.calls().last().map(String::as_str),
+            Some("sudo /usr/sbin/scutil --set HostName jane-mbp")
+        );
+    }
+}
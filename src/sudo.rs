@@ -1,4 +1,4 @@
-use std::process::{Command, Stdio};
+use std::rc::Rc;
 use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
@@ -6,10 +6,13 @@ use console::Term;
 use indicatif::ProgressBar;
 use tracing::debug;
 
+use crate::command::{CommandOutput, CommandRunner};
+
 const SUDO_TTL: Duration = Duration::from_secs(4 * 60);
 
-#[derive(Default)]
 pub struct SudoHelper {
+    runner: Rc<dyn CommandRunner>,
+    interactive: bool,
     valid_until: Option<Instant>,
     prompt_ui: Option<SudoPromptUi>,
 }
@@ -52,6 +55,17 @@ impl Drop for CursorGuard {
 }
 
 impl SudoHelper {
+    /// `interactive` controls whether sudo may prompt for a password; without
+    /// a terminal a missing ticket is reported instead of hanging.
+    pub fn new(runner: Rc<dyn CommandRunner>, interactive: bool) -> Self {
+        SudoHelper {
+            runner,
+            interactive,
+            valid_until: None,
+            prompt_ui: None,
+        }
+    }
+
     pub fn set_prompt_ui(&mut self, progress: ProgressBar) {
         self.prompt_ui = Some(SudoPromptUi::new(progress));
     }
@@ -62,61 +76,30 @@ impl SudoHelper {
 
     pub fn run(&mut self, program: &str, args: &[&str]) -> Result<()> {
         let output = self.exec(program, args)?;
-        if output.status.success() {
+        if output.success() {
             if !output.stdout.is_empty() {
-                debug!(
-                    "sudo {} stdout: {}",
-                    program,
-                    String::from_utf8_lossy(&output.stdout)
-                );
+                debug!("sudo {} stdout: {}", program, output.stdout);
             }
             if !output.stderr.is_empty() {
-                debug!(
-                    "sudo {} stderr: {}",
-                    program,
-                    String::from_utf8_lossy(&output.stderr)
-                );
+                debug!("sudo {} stderr: {}", program, output.stderr);
             }
             Ok(())
         } else {
-            Err(anyhow!(
-                "command `{}` failed: {}",
-                program,
-                String::from_utf8_lossy(&output.stderr)
-            ))
-        }
-    }
-
-    pub fn run_with_output(&mut self, program: &str, args: &[&str]) -> Result<String> {
-        let output = self.exec(program, args)?;
-        if output.status.success() {
-            Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
-        } else {
-            Err(anyhow!(
-                "command `{}` failed: {}",
-                program,
-                String::from_utf8_lossy(&output.stderr)
-            ))
+            Err(anyhow!("command `{}` failed: {}", program, output.stderr))
         }
     }
 
-    fn exec(&mut self, program: &str, args: &[&str]) -> Result<std::process::Output> {
+    fn exec(&mut self, program: &str, args: &[&str]) -> Result<CommandOutput> {
         self.ensure_ticket()?;
 
-        let mut child = Command::new("sudo");
-        child.arg(program);
-        child.args(args);
-        child.stdin(Stdio::inherit());
-        child.stdout(Stdio::piped());
-        child.stderr(Stdio::piped());
-
-        let output = child
-            .spawn()
-            .with_context(|| format!("unable to spawn sudo for {}", program))?
-            .wait_with_output()
-            .context("failed to wait for sudo command")?;
+        let mut sudo_args = vec![program];
+        sudo_args.extend_from_slice(args);
+        let output = self
+            .runner
+            .output("sudo", &sudo_args)
+            .with_context(|| format!("unable to spawn sudo for {}", program))?;
 
-        if !output.status.success() {
+        if !output.success() {
             // Force revalidation next time to avoid stale auth.
             self.valid_until = None;
         }
@@ -133,16 +116,22 @@ impl SudoHelper {
             return Ok(());
         }
 
-        if Command::new("sudo")
-            .args(["-n", "true"])
-            .status()
-            .map(|s| s.success())
+        if self
+            .runner
+            .output("sudo", &["-n", "true"])
+            .map(|output| output.success())
             .unwrap_or(false)
         {
             self.valid_until = Some(Instant::now() + SUDO_TTL);
             return Ok(());
         }
 
+        if !self.interactive {
+            return Err(anyhow!(
+                "sudo needs a password but no terminal is attached; run `sudo -v` beforehand or allow passwordless sudo"
+            ));
+        }
+
         self.prompt_through_sudo()
     }
 
@@ -155,11 +144,11 @@ impl SudoHelper {
     }
 
     fn refresh_credentials(&mut self) -> Result<()> {
-        let status = Command::new("sudo")
-            .arg("-v")
-            .status()
+        let output = self
+            .runner
+            .interactive("sudo", &["-v"])
             .context("failed to refresh sudo credentials")?;
-        if status.success() {
+        if output.success() {
             self.valid_until = Some(Instant::now() + SUDO_TTL);
             Ok(())
         } else {
@@ -167,3 +156,39 @@ impl SudoHelper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::ScriptedRunner;
+
+    #[test]
+    fn non_interactive_fails_fast_without_ticket() {
+        let runner = Rc::new(ScriptedRunner::new());
+        runner.on("sudo -n true", 1, "");
+        let mut sudo = SudoHelper::new(runner.clone(), false);
+
+        let err = sudo.run("/bin/true", &[]).unwrap_err();
+        assert!(err.to_string().contains("no terminal"));
+        assert_eq!(runner.calls(), ["sudo -n true"]);
+    }
+
+    #[test]
+    fn cached_ticket_runs_without_prompt() {
+        let runner = Rc::new(ScriptedRunner::new());
+        runner
+            .on("sudo -n true", 0, "")
+            .on("sudo /usr/sbin/systemsetup -settimezone UTC", 0, "");
+        let mut sudo = SudoHelper::new(runner.clone(), false);
+
+        sudo.run("/usr/sbin/systemsetup", &["-settimezone", "UTC"])
+            .unwrap();
+        assert_eq!(
+            runner.calls(),
+            [
+                "sudo -n true",
+                "sudo /usr/sbin/systemsetup -settimezone UTC"
+            ]
+        );
+    }
+}
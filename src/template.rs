@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use toml::{Table, Value};
+
+/// Values available to `{{ name }}` placeholders in config strings.
+///
+/// Names come from the built-ins, then `[vars]` (which may override them);
+/// `env.NAME` reads the environment.
+#[derive(Debug, Default)]
+pub struct Vars {
+    values: HashMap<String, String>,
+}
+
+impl Vars {
+    pub fn new(builtins: impl IntoIterator<Item = (&'static str, String)>) -> Self {
+        Vars {
+            values: builtins
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect(),
+        }
+    }
+
+    /// Adds the entries of a `[vars]` table. Values may use the built-ins
+    /// and environment but not each other.
+    pub fn extend_from_table(&mut self, table: Table) -> Result<()> {
+        let mut rendered = Vec::new();
+        for (name, value) in table {
+            let path = format!("vars.{name}");
+            let value = match value {
+                Value::String(text) => render_at(&text, self, &path)?,
+                Value::Integer(number) => number.to_string(),
+                Value::Float(number) => number.to_string(),
+                Value::Boolean(flag) => flag.to_string(),
+                _ => bail!("`{path}` must be a string, number or boolean"),
+            };
+            rendered.push((name, value));
+        }
+        self.values.extend(rendered);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        match name.strip_prefix("env.") {
+            Some(var) => std::env::var(var).ok(),
+            None => self.values.get(name).cloned(),
+        }
+    }
+}
+
+/// Renders every string in `table`, naming the offending key on error.
+pub fn render_table(table: &mut Table, vars: &Vars) -> Result<()> {
+    for (key, value) in table.iter_mut() {
+        render_value(value, vars, key)?;
+    }
+    Ok(())
+}
+
+fn render_value(value: &mut Value, vars: &Vars, path: &str) -> Result<()> {
+    match value {
+        Value::String(text) => *text = render_at(text, vars, path)?,
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                render_value(item, vars, &format!("{path}[{index}]"))?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                render_value(item, vars, &format!("{path}.{key}"))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Escapes every string in `table` so it renders back to itself.
+pub fn escape_table(table: &mut Table) {
+    for (_, value) in table.iter_mut() {
+        escape_value(value);
+    }
+}
+
+fn escape_value(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = escape(text),
+        Value::Array(items) => items.iter_mut().for_each(escape_value),
+        Value::Table(table) => escape_table(table),
+        _ => {}
+    }
+}
+
+/// `text` with each literal `{{` written as `{{ "{{" }}`.
+pub fn escape(text: &str) -> String {
+    text.replace("{{", r#"{{ "{{" }}"#)
+}
+
+fn render_at(text: &str, vars: &Vars, path: &str) -> Result<String> {
+    render(text, vars).map_err(|err| anyhow::anyhow!("{err} in `{path}`"))
+}
+
+/// Replaces each `{{ name }}` in `text` with its value, and each
+/// `{{ "text" }}` with `text`, which is how a literal `{{` is written.
+pub fn render(text: &str, vars: &Vars) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            bail!("unterminated `{{{{`");
+        };
+        let name = after[..end].trim();
+        if name.is_empty() {
+            bail!("empty `{{{{ }}}}` placeholder");
+        }
+        let literal = name
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'));
+        match literal.map(str::to_owned).or_else(|| vars.lookup(name)) {
+            Some(value) => out.push_str(&value),
+            None => bail!("undefined variable `{name}`"),
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        Vars::new([
+            ("user", "test".to_owned()),
+            ("home", "/Users/test".to_owned()),
+        ])
+    }
+
+    #[test]
+    fn placeholders_are_replaced() {
+        assert_eq!(
+            render("{{ home }}/src/{{user}}", &vars()).unwrap(),
+            "/Users/test/src/test"
+        );
+        assert_eq!(
+            render("no placeholders", &vars()).unwrap(),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn vars_table_can_use_builtins_and_override_them() {
+        let mut vars = vars();
+        let table: Table = toml::from_str(
+            r#"
+            email = "{{ user }}@example.com"
+            home = "/Volumes/home"
+            "#,
+        )
+        .unwrap();
+        vars.extend_from_table(table).unwrap();
+
+        assert_eq!(render("{{ email }}", &vars).unwrap(), "test@example.com");
+        assert_eq!(render("{{ home }}", &vars).unwrap(), "/Volumes/home");
+    }
+
+    #[test]
+    fn undefined_variables_name_the_key() {
+        let mut table: Table = toml::from_str(
+            r#"
+            [homebrew]
+            casks = ["iterm2", "{{ work_browser }}"]
+            "#,
+        )
+        .unwrap();
+
+        let err = render_table(&mut table, &vars()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "undefined variable `work_browser` in `homebrew.casks[1]`"
+        );
+    }
+
+    #[test]
+    fn quoted_braces_render_literally() {
+        assert_eq!(
+            render(r#"{{ "{{" }} user }}"#, &vars()).unwrap(),
+            "{{ user }}"
+        );
+
+        let text = "echo {{user}} and {{ home }}";
+        assert_eq!(render(&escape(text), &vars()).unwrap(), text);
+    }
+
+    #[test]
+    fn environment_is_available_under_env() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(render("{{ env.PATH }}", &vars()).unwrap(), path);
+        assert!(render("{{ env.SETUP_MY_MAC_SURELY_UNSET }}", &vars()).is_err());
+    }
+}
@@ -1,17 +1,74 @@
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
 
+/// Expands a leading `~` in `input`, resolving relative results against
+/// `base_dir`. Anything else is left to `{{ }}` templating.
 pub fn resolve_path(input: &str, base_dir: &Path) -> Result<PathBuf> {
-    let trimmed = input.trim();
-    if trimmed.starts_with('~') {
-        let expanded = shellexpand::tilde(trimmed).into_owned();
-        Ok(PathBuf::from(expanded))
-    } else if Path::new(trimmed).is_absolute() {
-        Ok(PathBuf::from(trimmed))
+    Ok(rebase(&shellexpand::tilde(input.trim()), base_dir))
+}
+
+/// Like [`resolve_path`], but `~` means `home` rather than the invoking
+/// user's home directory.
+pub fn resolve_path_in_home(input: &str, base_dir: &Path, home: &Path) -> Result<PathBuf> {
+    let expanded = shellexpand::tilde_with_context(input.trim(), || home.to_str());
+    Ok(rebase(&expanded, base_dir))
+}
+
+fn rebase(path: &str, base_dir: &Path) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_owned()
     } else {
-        Ok(base_dir.join(trimmed))
+        base_dir.join(path)
+    }
+}
+
+/// `path` moved under `root`, e.g. `/etc/hosts` becomes `<root>/etc/hosts`.
+pub fn reroot(path: &Path, root: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// A `file://` URL for a directory or bundle, as the Finder and Dock store it.
+pub fn file_url(path: &Path) -> String {
+    let mut url = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                url.push(byte as char)
+            }
+            _ => url.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    url.push('/');
+    url
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones as they are.
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
     }
+    String::from_utf8_lossy(&out).into_owned()
 }
 
 pub fn normalize_newlines(input: &str) -> String {
@@ -23,21 +80,71 @@ pub fn normalize_newlines(input: &str) -> String {
     normalized
 }
 
-pub fn write_if_changed(path: &Path, contents: &str) -> Result<bool> {
-    if path.exists() {
-        let existing = std::fs::read_to_string(path)
-            .with_context(|| format!("Failed to read {}", path.display()))?;
-        if normalize_newlines(&existing) == normalize_newlines(contents) {
-            return Ok(false);
-        }
+/// Whether the user can answer prompts: both stdin and stderr (where
+/// dialoguer and sudo draw) must be terminals.
+pub fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The machine's host name as reported by `hostname`.
+pub fn hostname() -> Option<String> {
+    let output = Command::new("hostname").output().ok()?;
+    let name = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    (output.status.success() && !name.is_empty()).then_some(name)
+}
+
+/// The login name of the user running the tool.
+pub fn current_user() -> Option<String> {
+    std::env::var("USER")
+        .ok()
+        .filter(|user| !user.is_empty())
+        .or_else(|| {
+            let output = Command::new("id").arg("-un").output().ok()?;
+            let name = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+            (output.status.success() && !name.is_empty()).then_some(name)
+        })
+}
+
+/// The user a run is on behalf of: the one who invoked `sudo` when running
+/// under it, otherwise [`current_user`].
+pub fn invoking_user() -> Option<String> {
+    let current = current_user()?;
+    if current != "root" {
+        return Some(current);
     }
+    std::env::var("SUDO_USER")
+        .ok()
+        .filter(|user| !user.is_empty())
+        .or(Some(current))
+}
 
-    if let Some(parent) = path.parent() {
-        std::fs::create_dir_all(parent)
-            .with_context(|| format!("Failed to create {}", parent.display()))?;
+/// Home directory of `user`, as the shell expands `~user`.
+pub fn home_of(user: &str) -> Option<String> {
+    let safe = user
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !safe || user.is_empty() {
+        return None;
     }
+    let output = Command::new("/bin/sh")
+        .args(["-c", &format!("echo ~{user}")])
+        .output()
+        .ok()?;
+    let home = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    (output.status.success() && home.starts_with('/')).then_some(home)
+}
 
-    std::fs::write(path, contents)
-        .with_context(|| format!("Failed to write {}", path.display()))?;
-    Ok(true)
+/// CPU architecture in the spelling macOS uses (`arm64`, `x86_64`).
+pub fn arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "aarch64" => "arm64",
+        other => other,
+    }
 }
@@ -0,0 +1,670 @@
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use console::style;
+use serde_path_to_error::Segment;
+use toml::{
+    de::{DeTable, DeValue},
+    Spanned, Table, Value,
+};
+
+use crate::{
+    config::{self, Config, RawConfig},
+    defaults::DefaultsValue,
+    steps,
+    util::resolve_path_in_home,
+};
+
+const CONFLICT_STYLES: [&str; 3] = ["merge", "diff3", "zdiff3"];
+
+/// Icon sizes the Dock accepts for `tilesize` and `largesize`.
+const DOCK_SIZES: std::ops::RangeInclusive<i64> = 16..=128;
+
+/// Key repeat delays `defaults` accepts, in 15 ms units.
+const KEY_REPEATS: std::ops::RangeInclusive<i64> = 1..=120;
+
+/// Named Finder locations; anything else must be a path.
+const NEW_WINDOW_TARGETS: [&str; 4] = ["home", "desktop", "documents", "computer"];
+
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+
+/// Checks the config at `path` and prints every problem found.
+pub fn run(path: &Path, profile: Option<&str>) -> Result<()> {
+    let diagnostics = match config::load_raw(path, profile) {
+        Ok(raw) => check(&raw),
+        Err(err) => vec![Diagnostic::new(format!("{err:#}"))],
+    };
+
+    if diagnostics.is_empty() {
+        println!("{} {} is valid", style("✔").green().bold(), path.display());
+        return Ok(());
+    }
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+    bail!(
+        "{} problem(s) found in {}",
+        diagnostics.len(),
+        path.display()
+    );
+}
+
+/// Runs structural and semantic checks against a loaded config.
+pub fn check(raw: &RawConfig) -> Vec<Diagnostic> {
+    let sources = Sources::parse(raw);
+    let mut diagnostics = Vec::new();
+
+    // Serde stops at the first unknown key, so drop each one and retry to
+    // report them all in one pass.
+    let mut table = raw.table.clone();
+    let config = loop {
+        let err = match serde_path_to_error::deserialize::<_, Config>(Value::Table(table.clone())) {
+            Ok(config) => break Some(config),
+            Err(err) => err,
+        };
+        let key_path: Vec<String> = err
+            .path()
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Map { key } => Some(key.clone()),
+                Segment::Seq { index } => Some(index.to_string()),
+                _ => None,
+            })
+            .collect();
+        let message = err.inner().message();
+
+        let Some((field, expected)) = parse_unknown_field(message) else {
+            diagnostics.push(
+                Diagnostic::new(format!("`{}`: {message}", dotted(&key_path)))
+                    .at(sources.locate(&key_path, Span::Value)),
+            );
+            break None;
+        };
+
+        let mut key_path = key_path;
+        if key_path.last() != Some(&field) {
+            key_path.push(field.clone());
+        }
+        let parent = &key_path[..key_path.len() - 1];
+        let scope = if parent.is_empty() {
+            "at the top level".to_owned()
+        } else {
+            format!("in [{}]", dotted(parent))
+        };
+        diagnostics.push(
+            Diagnostic::new(format!("unknown key `{field}` {scope}"))
+                .at(sources.locate(&key_path, Span::Key))
+                .help(suggest(&field, expected.iter().map(String::as_str))),
+        );
+        if !remove(&mut table, &key_path) {
+            break None;
+        }
+    };
+
+    if let Some(config) = config {
+        diagnostics.extend(check_values(raw, &config, &sources));
+    }
+    diagnostics
+}
+
+fn check_values(raw: &RawConfig, cfg: &Config, sources: &Sources) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let at = |path: &[&str]| {
+        let path: Vec<String> = path.iter().map(|segment| (*segment).to_owned()).collect();
+        sources.locate(&path, Span::Value)
+    };
+
+    for (index, name) in cfg.disabled_steps.iter().enumerate() {
+        if steps::find(name).is_none() {
+            diagnostics.push(
+                Diagnostic::new(format!("unknown step `{name}` in disabled_steps"))
+                    .at(at(&["disabled_steps", &index.to_string()]))
+                    .help(suggest(name, steps::names())),
+            );
+        }
+    }
+
+    if let Some(tz) = &cfg.system.timezone {
+        if !is_timezone(tz) {
+            diagnostics.push(
+                Diagnostic::new(format!("`{tz}` is not an IANA timezone name"))
+                    .at(at(&["system", "timezone"]))
+                    .help(Some(
+                        "names look like `Europe/Berlin`; `sudo systemsetup -listtimezones` lists them all"
+                            .to_owned(),
+                    )),
+            );
+        }
+    }
+
+    let local_host_name = match &cfg.system.local_host_name {
+        Some(name) => Some((name, "local_host_name")),
+        None => cfg.system.hostname.as_ref().map(|name| (name, "hostname")),
+    };
+    if let Some((name, field)) = local_host_name {
+        if !is_local_host_name(name) {
+            let help = match field {
+                "hostname" => "hostname is also used as the local hostname; set local_host_name to override it",
+                _ => "use letters, digits and hyphens, up to 63 characters, not starting or ending with a hyphen",
+            };
+            diagnostics.push(
+                Diagnostic::new(format!("`{name}` is not a valid LocalHostName"))
+                    .at(at(&["system", field]))
+                    .help(Some(help.to_owned())),
+            );
+        }
+    }
+
+    for (index, entry) in cfg.system.defaults.iter().enumerate() {
+        if let Err(err) = DefaultsValue::from_config(entry.kind, &entry.value) {
+            diagnostics.push(
+                Diagnostic::new(format!("defaults {} {}: {err}", entry.domain, entry.key))
+                    .at(at(&["system", "defaults", &index.to_string(), "value"])),
+            );
+        }
+    }
+
+    if let Some(dock) = &cfg.system.dock {
+        for (field, size) in [
+            ("tile_size", dock.tile_size),
+            ("large_size", dock.large_size),
+        ] {
+            let Some(size) = size else { continue };
+            if !DOCK_SIZES.contains(&size) {
+                diagnostics.push(
+                    Diagnostic::new(format!(
+                        "Dock {field} must be between {} and {}, not {size}",
+                        DOCK_SIZES.start(),
+                        DOCK_SIZES.end()
+                    ))
+                    .at(at(&["system", "dock", field])),
+                );
+            }
+        }
+    }
+
+    let keyboard = &cfg.system.keyboard;
+    let repeats = [
+        ("key_repeat", keyboard.key_repeat),
+        ("initial_key_repeat", keyboard.initial_key_repeat),
+    ];
+    for (field, value) in repeats {
+        let Some(value) = value else { continue };
+        if !KEY_REPEATS.contains(&value) {
+            diagnostics.push(
+                Diagnostic::new(format!(
+                    "{field} must be between {} and {}, not {value}",
+                    KEY_REPEATS.start(),
+                    KEY_REPEATS.end()
+                ))
+                .at(at(&["system", "keyboard", field])),
+            );
+        }
+    }
+
+    if let Some(target) = &cfg.system.finder.new_window_target {
+        let is_path = target.starts_with('/') || target.starts_with('~');
+        if steps::system::new_window_code(target).is_none() && !is_path {
+            diagnostics.push(
+                Diagnostic::new(format!(
+                    "new_window_target must be one of {} or a path, not `{target}`",
+                    NEW_WINDOW_TARGETS.join(", ")
+                ))
+                .at(at(&["system", "finder", "new_window_target"]))
+                .help(suggest(target, NEW_WINDOW_TARGETS)),
+            );
+        }
+    }
+
+    let mut dotfiles: Vec<_> = cfg.user.dotfiles.iter().collect();
+    dotfiles.sort_by_key(|(name, _)| *name);
+    for (name, entry) in dotfiles {
+        let home = &cfg.system.home_directory;
+        let exists = resolve_path_in_home(&entry.source, raw.dotfile_root(name), home)
+            .map(|source| source.exists())
+            .unwrap_or(false);
+        if !exists {
+            diagnostics.push(
+                Diagnostic::new(format!(
+                    "dotfile source `{}` for `{name}` does not exist",
+                    entry.source
+                ))
+                .at(at(&["user", "dotfiles", name, "source"])),
+            );
+        }
+    }
+
+    let conflictstyle = cfg
+        .user
+        .git
+        .as_ref()
+        .and_then(|git| git.merge.as_ref())
+        .and_then(|merge| merge.conflictstyle.as_deref());
+    if let Some(conflictstyle) = conflictstyle {
+        if !CONFLICT_STYLES.contains(&conflictstyle) {
+            diagnostics.push(
+                Diagnostic::new(format!(
+                    "conflictstyle must be one of {}, not `{conflictstyle}`",
+                    CONFLICT_STYLES.join(", ")
+                ))
+                .at(at(&["user", "git", "merge", "conflictstyle"]))
+                .help(suggest(conflictstyle, CONFLICT_STYLES)),
+            );
+        }
+    }
+
+    diagnostics
+}
+
+/// Whether `name` works as a Bonjour name: one DNS label of letters, digits
+/// and hyphens.
+pub fn is_local_host_name(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Whether `name` is a zone in the system's tz database.
+pub fn is_timezone(name: &str) -> bool {
+    let valid_name = !name.is_empty()
+        && !name.starts_with('/')
+        && name
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..");
+    valid_name && Path::new(ZONEINFO).join(name).is_file()
+}
+
+/// Splits serde's "unknown field `x`, expected ..." into the field and the
+/// names it would have accepted.
+fn parse_unknown_field(message: &str) -> Option<(String, Vec<String>)> {
+    let rest = message.strip_prefix("unknown field `")?;
+    let (field, rest) = rest.split_once('`')?;
+    let expected = rest
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(str::to_owned)
+        .collect();
+    Some((field.to_owned(), expected))
+}
+
+fn suggest<'a>(input: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    candidates
+        .into_iter()
+        .map(|candidate| (strsim::jaro_winkler(input, candidate), candidate))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| format!("did you mean `{candidate}`?"))
+}
+
+fn remove(table: &mut Table, path: &[String]) -> bool {
+    match path {
+        [] => false,
+        [key] => table.remove(key).is_some(),
+        [key, rest @ ..] => table
+            .get_mut(key)
+            .is_some_and(|inner| remove_in(inner, rest)),
+    }
+}
+
+/// Like [`remove`], starting from a value that may be an array of tables
+/// indexed by the next path segment.
+fn remove_in(value: &mut Value, path: &[String]) -> bool {
+    match (value, path) {
+        (Value::Table(table), _) => remove(table, path),
+        (Value::Array(items), [index, rest @ ..]) => index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| items.get_mut(index))
+            .is_some_and(|item| remove_in(item, rest)),
+        _ => false,
+    }
+}
+
+fn dotted(path: &[String]) -> String {
+    path.join(".")
+}
+
+#[derive(Copy, Clone)]
+enum Span {
+    Key,
+    Value,
+}
+
+/// The parsed source files, used to map key paths back to positions.
+struct Sources<'a> {
+    files: Vec<(&'a Path, &'a str, Spanned<DeTable<'a>>)>,
+    profiles: &'a [String],
+}
+
+impl<'a> Sources<'a> {
+    fn parse(raw: &'a RawConfig) -> Self {
+        let files = raw
+            .sources
+            .iter()
+            .filter_map(|(path, text)| {
+                DeTable::parse(text)
+                    .ok()
+                    .map(|table| (path.as_path(), text.as_str(), table))
+            })
+            .collect();
+        Sources {
+            files,
+            profiles: &raw.profiles,
+        }
+    }
+
+    /// Finds where `path` is set, preferring later files and applied
+    /// profiles since their values win the merge.
+    fn locate(&self, path: &[String], span: Span) -> Option<Location> {
+        for (file, text, table) in self.files.iter().rev() {
+            for profile in self.profiles.iter().rev() {
+                let prefixed: Vec<String> = ["profiles".to_owned(), profile.clone()]
+                    .into_iter()
+                    .chain(path.iter().cloned())
+                    .collect();
+                if let Some(range) = find(table.get_ref(), &prefixed, span) {
+                    return Some(Location::new(file, text, range));
+                }
+            }
+            if let Some(range) = find(table.get_ref(), path, span) {
+                return Some(Location::new(file, text, range));
+            }
+        }
+        None
+    }
+}
+
+fn find(table: &DeTable<'_>, path: &[String], span: Span) -> Option<Range<usize>> {
+    let (first, rest) = path.split_first()?;
+    let (key, value) = table.iter().find(|(key, _)| key.get_ref() == first)?;
+    if rest.is_empty() {
+        return Some(match span {
+            Span::Key => key.span(),
+            Span::Value => value.span(),
+        });
+    }
+    find_in_value(value, rest, span)
+}
+
+fn find_in_value(
+    value: &Spanned<DeValue<'_>>,
+    path: &[String],
+    span: Span,
+) -> Option<Range<usize>> {
+    match value.get_ref() {
+        DeValue::Table(table) => find(table, path, span),
+        DeValue::Array(items) => {
+            let (index, rest) = path.split_first()?;
+            let item = items.get(index.parse::<usize>().ok()?)?;
+            if rest.is_empty() {
+                Some(item.span())
+            } else {
+                find_in_value(item, rest, span)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// A position in a config file, with the line it points into.
+#[derive(Debug)]
+pub struct Location {
+    file: PathBuf,
+    line: usize,
+    column: usize,
+    source_line: String,
+    width: usize,
+}
+
+impl Location {
+    fn new(file: &Path, text: &str, range: Range<usize>) -> Self {
+        let line_start = text[..range.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[range.start..]
+            .find('\n')
+            .map_or(text.len(), |i| range.start + i);
+        let source_line = text[line_start..line_end].trim_end_matches('\r');
+        Location {
+            file: file.to_owned(),
+            line: text[..range.start].matches('\n').count() + 1,
+            column: text[line_start..range.start].chars().count() + 1,
+            width: text[range.start..range.end.min(line_end)]
+                .chars()
+                .count()
+                .max(1),
+            source_line: source_line.to_owned(),
+        }
+    }
+}
+
+/// One problem found by `validate`.
+#[derive(Debug)]
+pub struct Diagnostic {
+    message: String,
+    location: Option<Location>,
+    help: Option<String>,
+}
+
+impl Diagnostic {
+    fn new(message: impl Into<String>) -> Self {
+        Diagnostic {
+            message: message.into(),
+            location: None,
+            help: None,
+        }
+    }
+
+    fn at(mut self, location: Option<Location>) -> Self {
+        self.location = location;
+        self
+    }
+
+    fn help(mut self, help: Option<String>) -> Self {
+        self.help = help;
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {}",
+            style("error").red().bold(),
+            style(&self.message).bold()
+        )?;
+        let gutter = self
+            .location
+            .as_ref()
+            .map_or(1, |location| location.line.to_string().len());
+        let pad = " ".repeat(gutter);
+        if let Some(location) = &self.location {
+            let bar = style("|").blue().bold();
+            writeln!(
+                f,
+                "{pad}{} {}:{}:{}",
+                style("-->").blue().bold(),
+                location.file.display(),
+                location.line,
+                location.column
+            )?;
+            writeln!(f, "{pad} {bar}")?;
+            writeln!(
+                f,
+                "{} {bar} {}",
+                style(location.line).blue().bold(),
+                location.source_line
+            )?;
+            writeln!(
+                f,
+                "{pad} {bar} {}{}",
+                " ".repeat(location.column - 1),
+                style("^".repeat(location.width)).red().bold()
+            )?;
+        }
+        if let Some(help) = &self.help {
+            writeln!(f, "{pad} {} help: {help}", style("=").blue().bold())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const BASE: &str = r#"[system]
+home_directory = "/Users/test"
+primary_user = "test"
+
+[system.trackpad]
+three_fingers_drag = true
+
+[user]
+"#;
+
+    fn diagnostics(dir: &Path, contents: &str) -> Vec<String> {
+        let path = dir.join("config.toml");
+        fs::write(&path, contents).unwrap();
+        let raw = config::load_raw(&path, None).unwrap();
+        check(&raw)
+            .iter()
+            .map(|diagnostic| console::strip_ansi_codes(&diagnostic.to_string()).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_spans_and_suggestions() {
+        let dir = tempfile::tempdir().unwrap();
+        let found = diagnostics(dir.path(), &format!("{BASE}\n[homebrew]\nenabled = true\n"));
+
+        let config = dir.path().join("config.toml").display().to_string();
+        assert_eq!(
+            found,
+            [
+                [
+                    "error: unknown key `enabled` in [homebrew]".to_owned(),
+                    format!("  --> {config}:11:1"),
+                    "   |".to_owned(),
+                    "11 | enabled = true".to_owned(),
+                    "   | ^^^^^^^".to_owned(),
+                    "   = help: did you mean `enable`?\n".to_owned(),
+                ]
+                .join("\n"),
+                [
+                    "error: unknown key `three_fingers_drag` in [system.trackpad]".to_owned(),
+                    format!(" --> {config}:6:1"),
+                    "  |".to_owned(),
+                    "6 | three_fingers_drag = true".to_owned(),
+                    "  | ^^^^^^^^^^^^^^^^^^".to_owned(),
+                    "  = help: did you mean `three_finger_drag`?\n".to_owned(),
+                ]
+                .join("\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_inside_arrays_of_tables_are_all_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let found = diagnostics(
+            dir.path(),
+            &format!(
+                "{BASE}\n[[system.defaults]]\ndomain = \"com.apple.dock\"\nkey = \"autohide\"\ncurent_host = true\ntype = \"bool\"\nvalue = true\nnote = \"hide it\"\n"
+            ),
+        );
+
+        let headlines: Vec<&str> = found
+            .iter()
+            .filter_map(|diagnostic| diagnostic.lines().next())
+            .collect();
+        assert_eq!(
+            headlines,
+            [
+                "error: unknown key `curent_host` in [system.defaults.0]",
+                "error: unknown key `note` in [system.defaults.0]",
+                "error: unknown key `three_fingers_drag` in [system.trackpad]",
+            ]
+        );
+    }
+
+    #[test]
+    fn values_are_checked_once_the_structure_is_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let found = diagnostics(
+            dir.path(),
+            r#"disabled_steps = ["homebrw"]
+
+[system]
+home_directory = "/Users/test"
+primary_user = "test"
+timezone = "Mars/Olympus_Mons"
+hostname = "jane-mbp.corp.example.com"
+
+[[system.defaults]]
+domain = "com.apple.dock"
+key = "autohide"
+type = "bool"
+value = "yes"
+
+[system.dock]
+tile_size = 256
+
+[system.finder]
+new_window_target = "desktp"
+
+[system.keyboard]
+key_repeat = 0
+
+[user.dotfiles.zsh]
+source = "missing/zshrc"
+target = "~/.zshrc"
+
+[user.git.merge]
+conflictstyle = "diff-3"
+"#,
+        );
+
+        let headlines: Vec<_> = found
+            .iter()
+            .map(|diagnostic| diagnostic.lines().next().unwrap())
+            .collect();
+        assert_eq!(
+            headlines,
+            [
+                "error: unknown step `homebrw` in disabled_steps",
+                "error: `Mars/Olympus_Mons` is not an IANA timezone name",
+                "error: `jane-mbp.corp.example.com` is not a valid LocalHostName",
+                "error: defaults com.apple.dock autohide: expected bool value, found string",
+                "error: Dock tile_size must be between 16 and 128, not 256",
+                "error: key_repeat must be between 1 and 120, not 0",
+                "error: new_window_target must be one of home, desktop, documents, computer or a path, not `desktp`",
+                "error: dotfile source `missing/zshrc` for `zsh` does not exist",
+                "error: conflictstyle must be one of merge, diff3, zdiff3, not `diff-3`",
+            ]
+        );
+        assert!(found[0].contains(":1:19\n"), "{}", found[0]);
+        assert!(found[0].contains("did you mean `homebrew`?"));
+        assert!(found[2].contains("set local_host_name to override it"));
+        assert!(found[3].contains(":13:9\n"), "{}", found[3]);
+        assert!(found[6].contains("did you mean `desktop`?"));
+        assert!(found[8].contains("did you mean `diff3`?"));
+    }
+
+    #[test]
+    fn unknown_field_messages_are_parsed() {
+        assert_eq!(
+            parse_unknown_field("unknown field `sourc`, expected `source` or `target`"),
+            Some((
+                "sourc".to_owned(),
+                vec!["source".to_owned(), "target".to_owned()]
+            ))
+        );
+        assert_eq!(parse_unknown_field("missing field `system`"), None);
+    }
+}